@@ -4,6 +4,7 @@ use std::path::{Path, PathBuf};
 use structopt::clap::Shell;
 use structopt::StructOpt;
 
+#[allow(dead_code)]
 #[path = "src/cli.rs"]
 mod cli;
 
@@ -1,6 +1,7 @@
 //! Command line options for mdevctl
 
 use std::path::PathBuf;
+use std::time::Duration;
 pub use structopt::StructOpt;
 use uuid::Uuid;
 
@@ -11,8 +12,26 @@ pub struct LsmdevOptions {
     pub defined: bool,
     #[structopt(long, help = "Output device list in json format")]
     pub dumpjson: bool,
-    #[structopt(short, long, help = "Print additional information about the devices")]
-    pub verbose: bool,
+    #[structopt(
+        long,
+        requires("dumpjson"),
+        conflicts_with("output-dir"),
+        help = "Include whether each device is active and defined in the json output"
+    )]
+    pub include_state: bool,
+    #[structopt(
+        short,
+        long,
+        parse(from_occurrences),
+        help = "Print additional information about the devices, more when repeated"
+    )]
+    pub verbose: u8,
+    #[structopt(
+        long,
+        requires("verbose"),
+        help = "Mark attributes whose live value differs from the definition"
+    )]
+    pub diff_attrs: bool,
     #[structopt(short, long, help = "List devices matching the specified UUID")]
     pub uuid: Option<Uuid>,
     #[structopt(
@@ -21,6 +40,62 @@ pub struct LsmdevOptions {
         help = "List devices associated with the specified Parent device"
     )]
     pub parent: Option<String>,
+    #[structopt(
+        long,
+        conflicts_with("parent"),
+        help = "List devices whose parent device name matches the specified regular expression"
+    )]
+    pub parent_regex: Option<String>,
+    #[structopt(
+        long,
+        value_name = "KEY[=VALUE]",
+        help = "List devices with an attribute of this name, and value if given"
+    )]
+    pub match_attr: Option<String>,
+    #[structopt(
+        long,
+        alias = "unmanaged",
+        conflicts_with("defined"),
+        help = "List only active devices which are not defined"
+    )]
+    pub undefined_only: bool,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        requires("dumpjson"),
+        help = "Write the json of each device to a file below this directory instead of printing it"
+    )]
+    pub output_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "seconds",
+        help = "Stop looking up defined devices after this many seconds and list those found so far"
+    )]
+    pub timeout: Option<u64>,
+    #[structopt(long, help = "Exit with an error if no devices are found")]
+    pub fail_empty: bool,
+    #[structopt(
+        long,
+        conflicts_with_all(&["output-dir", "fail-empty"]),
+        help = "Keep refreshing the list until interrupted"
+    )]
+    pub watch: bool,
+    #[structopt(
+        long,
+        value_name = "seconds",
+        default_value = "2",
+        parse(try_from_str = parse_seconds),
+        help = "Number of seconds between refreshes of the list with --watch"
+    )]
+    pub interval: Duration,
+    #[structopt(
+        long,
+        value_name = "mode",
+        possible_values(&["null"]),
+        conflicts_with_all(&["verbose", "watch"]),
+        help = "With 'null', print nothing and report the result only by the exit code"
+    )]
+    pub output: Option<String>,
 }
 
 // command-line argument definitions.
@@ -39,8 +114,13 @@ pub enum MdevctlCommands {
                 If the device specified by the UUID currently exists, 'parent' and 'type' may be \
                 omitted to use the existing values. The 'auto' option marks the device to start on \
                 parent availability.  If defined via 'jsonfile', then 'type', 'startup', and any \
-                attributes are provided via the file.\n\n\
-                Running devices are unaffected by this command."
+                attributes are provided via the file. The file may also provide the 'parent', which \
+                is overridden by the 'parent' option if both are given.\n\n\
+                Running devices are unaffected by this command. With 'start', the device is started \
+                once it is defined, and the definition is removed again if the device fails to \
+                start unless 'keep-on-failure' is given. With 'dedup', attributes which repeat \
+                both the name and the value of an earlier attribute are removed, keeping the first \
+                occurrence."
     )]
     Define {
         #[structopt(
@@ -76,6 +156,40 @@ pub enum MdevctlCommands {
             help = "Specify device details in JSON format"
         )]
         jsonfile: Option<PathBuf>,
+        #[structopt(long, help = "Start the device once it is defined")]
+        start: bool,
+        #[structopt(
+            long,
+            requires("start"),
+            help = "Keep the definition if the device fails to start"
+        )]
+        keep_on_failure: bool,
+        #[structopt(long, help = "Remove attributes repeating an earlier name and value")]
+        dedup: bool,
+        #[structopt(long, help = "Print the resulting device in JSON format on success")]
+        print_json_on_success: bool,
+    },
+
+    #[structopt(
+        about = "Define a persistent mediated device from an active device",
+        long_about = "Define a persistent mediated device from an active device\n\n\
+                Creates a definition for an active device that was not defined, for example a \
+                device created outside of mdevctl. The parent, type, and the current values of \
+                the device's readable and writable attributes are taken from the running device. \
+                The 'auto' option marks the device to start on parent availability. An existing \
+                definition is only replaced if 'force' is specified."
+    )]
+    Adopt {
+        #[structopt(short, long, help = "UUID of the active device to adopt")]
+        uuid: Uuid,
+        #[structopt(
+            short,
+            long,
+            help = "Automatically start device on parent availability"
+        )]
+        auto: bool,
+        #[structopt(short, long, help = "Replace an existing definition")]
+        force: bool,
     },
 
     #[structopt(
@@ -83,13 +197,86 @@ pub enum MdevctlCommands {
         long_about = "Undefine, or remove a config for an mdev device\n\n\
                 If a UUID exists for multiple parents, all will be removed unless a parent is
                 specified. \n\n\
+                Instead of a UUID, the devices to undefine can be selected by 'type' and/or \
+                'uuid-prefix', optionally restricted to a 'parent'. All defined devices matching \
+                every given filter are undefined. With 'dry-run', the matching devices are only \
+                listed. Otherwise the number of matching devices and some of their UUIDs are \
+                shown and confirmation is asked for, unless 'yes' is given, which is required if \
+                the standard input is not a terminal.\n\n\
                 Running devices are unaffected by this command."
     )]
     Undefine {
-        #[structopt(short, long, help = "UUID of the device to be undefined")]
-        uuid: Uuid,
+        #[structopt(
+            short,
+            long,
+            required_unless_one(&["type", "uuid-prefix"]),
+            help = "UUID of the device to be undefined"
+        )]
+        uuid: Option<Uuid>,
         #[structopt(short, long, help = "Parent of the device to be undefined")]
         parent: Option<String>,
+        #[structopt(
+            name = "type",
+            short,
+            long,
+            conflicts_with("uuid"),
+            help = "Undefine all devices of this mdev type"
+        )]
+        mdev_type: Option<String>,
+        #[structopt(
+            long,
+            conflicts_with("uuid"),
+            help = "Undefine all devices whose UUID starts with this prefix"
+        )]
+        uuid_prefix: Option<String>,
+        #[structopt(
+            long,
+            conflicts_with("uuid"),
+            help = "List the devices that would be undefined without undefining them"
+        )]
+        dry_run: bool,
+        #[structopt(
+            short,
+            long,
+            conflicts_with("uuid"),
+            help = "Undefine the matching devices without asking for confirmation"
+        )]
+        yes: bool,
+    },
+
+    #[structopt(
+        about = "Export the definition of a mediated device",
+        long_about = "Export the definition of a mediated device\n\n\
+                Prints the stored configuration of a defined device in JSON format. The output \
+                can be edited and used to define a device via 'jsonfile'. If a UUID exists for \
+                multiple parents, the parent must be specified. With 'flat-attrs', attributes are \
+                output as a single object for consumers which prefer it. Attributes in this form \
+                are not read from a 'jsonfile', and it fails if an attribute is listed more than \
+                once. With 'sort-attrs', attributes are sorted by name to give stable output for \
+                backups and diffs. Attributes are written in the order they are listed, so sorted \
+                output must not be used to recreate a device whose attributes depend on that \
+                order. With 'output=null', nothing but errors is printed and the result is only \
+                reported by the exit code."
+    )]
+    Export {
+        #[structopt(short, long, help = "UUID of the device to export")]
+        uuid: Uuid,
+        #[structopt(short, long, help = "Parent of the device to export")]
+        parent: Option<String>,
+        #[structopt(
+            long,
+            help = "Output attributes as a single object rather than an array of objects"
+        )]
+        flat_attrs: bool,
+        #[structopt(long, help = "Sort attributes by name for stable output")]
+        sort_attrs: bool,
+        #[structopt(
+            long,
+            value_name = "mode",
+            possible_values(&["null"]),
+            help = "With 'null', print nothing and report the result only by the exit code"
+        )]
+        output: Option<String>,
     },
 
     #[structopt(
@@ -99,12 +286,21 @@ pub enum MdevctlCommands {
                 device cannot be modified via this command; undefine and re-define should be used \
                 instead. An attribute can be added or removed, which correlates to a sysfs \
                 attribute under the created device. Unless an 'index' value is provided, operations \
-                are performed at the end of the attribute list. 'value' is to be specified in the \
+                are performed at the end of the attribute list. Attributes can also be removed by \
+                name with 'delattr-name', which removes the first matching attribute, or all of \
+                them with 'all'. 'value' is to be specified in the \
                 format that is accepted by the attribute. Upon device start, mdevctl will go \
                 through each attribute in order, writing the value into the corresponding sysfs \
                 attribute for the device. The startup mode of the device can also be selected, auto \
                 or manual. \n\n\
-                Running devices are unaffected by this command."
+                Running devices are unaffected by this command unless 'apply-now' is specified. In \
+                that case the definition is updated first, then any added attributes are written \
+                to the running device. Changes which cannot be applied to a running device are \
+                reported and take effect the next time the device is started. \n\n\
+                The UUID of a device that isn't running can be changed with 'new-uuid', as long as \
+                no device is defined with the new UUID. With 'dedup', attributes which repeat both \
+                the name and the value of an earlier attribute are removed after any other change, \
+                keeping the first occurrence."
     )]
     Modify {
         #[structopt(short, long, help = "UUID of the mdev to modify")]
@@ -128,6 +324,19 @@ pub enum MdevctlCommands {
         addattr: Option<String>,
         #[structopt(long, help = "Delete an attribute")]
         delattr: bool,
+        #[structopt(
+            long,
+            conflicts_with_all(&["addattr", "delattr", "index"]),
+            help = "Delete the first attribute with the given name",
+            value_name = "attr_name"
+        )]
+        delattr_name: Option<String>,
+        #[structopt(
+            long,
+            requires("delattr-name"),
+            help = "Delete every attribute with the name given by --delattr-name"
+        )]
+        all: bool,
         #[structopt(long, short, help = "Index of the attribute to modify")]
         index: Option<u32>,
         #[structopt(
@@ -145,6 +354,32 @@ pub enum MdevctlCommands {
             help = "Device must be started manually"
         )]
         manual: bool,
+        #[structopt(
+            long,
+            help = "Also apply the changes to the device if it is currently running"
+        )]
+        apply_now: bool,
+        #[structopt(
+            long,
+            conflicts_with("apply-now"),
+            help = "Show the changes to the definition without applying them"
+        )]
+        dry_run: bool,
+        #[structopt(
+            long,
+            conflicts_with("apply-now"),
+            help = "Change the UUID of the device to this one",
+            value_name = "uuid"
+        )]
+        new_uuid: Option<Uuid>,
+        #[structopt(long, help = "Remove attributes repeating an earlier name and value")]
+        dedup: bool,
+        #[structopt(
+            long,
+            conflicts_with("dry-run"),
+            help = "Print the resulting device in JSON format on success"
+        )]
+        print_json_on_success: bool,
     },
     #[structopt(
         about = "Start a mediated device",
@@ -156,7 +391,12 @@ pub enum MdevctlCommands {
                 specified and will be started based only on these parameters.  The UUID is optional \
                 in this case. If not provided, a UUID is generated and returned as output. A \
                 'jsonfile' may replace the 'type' specification and also include additional \
-                attributes to be applied to the started device."
+                attributes to be applied to the started device. The 'parent' may also be provided \
+                by the 'jsonfile' if it is not given as an option. If the device is already \
+                running with the same parent and type but different attributes, a warning is \
+                issued, or an error with 'strict'. With 'retries', a start which fails because the \
+                device is busy is rolled back and attempted again after 'retry-delay' seconds, which helps with drivers that \
+                fail intermittently."
     )]
     Start {
         #[structopt(
@@ -179,14 +419,62 @@ pub enum MdevctlCommands {
             long,
             parse(from_os_str),
             conflicts_with("type"),
-            help = "Details of the device to be started, in JSON format"
+            help = "Details of the device to be started, in JSON format, or - to read them from stdin"
         )]
         jsonfile: Option<PathBuf>,
+        #[structopt(
+            long,
+            help = "Fail if the device is already running with different attributes"
+        )]
+        strict: bool,
+        #[structopt(
+            long,
+            parse(try_from_str = parse_seconds),
+            help = "Give up and remove the device if it isn't set up within this many seconds"
+        )]
+        timeout: Option<Duration>,
+        #[structopt(
+            long,
+            alias = "retry",
+            default_value = "0",
+            help = "Retry starting the device this many times if it is busy"
+        )]
+        retries: u32,
+        #[structopt(
+            long,
+            value_name = "seconds",
+            default_value = "1",
+            parse(try_from_str = parse_seconds),
+            help = "Number of seconds to wait between attempts to start the device"
+        )]
+        retry_delay: Duration,
+        #[structopt(long, help = "Print the resulting device in JSON format on success")]
+        print_json_on_success: bool,
     },
-    #[structopt(about = "Stop a mediated device")]
+    #[structopt(
+        about = "Stop a mediated device",
+        long_about = "Stop a mediated device\n\n\
+                A device that is in use may refuse to be removed. With 'force', removal of a busy \
+                device is retried for a short time before giving up. Some drivers remove a device \
+                asynchronously. With 'wait', the command only succeeds once the device is gone, \
+                waiting up to the given number of seconds for it."
+    )]
     Stop {
         #[structopt(short, long, help = "UUID of the device to stop")]
         uuid: Uuid,
+        #[structopt(
+            short,
+            long,
+            help = "Retry removal for a short time if the device is busy"
+        )]
+        force: bool,
+        #[structopt(
+            long,
+            value_name = "seconds",
+            parse(try_from_str = parse_seconds),
+            help = "Wait up to this many seconds for the device to be gone"
+        )]
+        wait: Option<Duration>,
     },
     #[structopt(
         about = "List mediated devices",
@@ -199,21 +487,174 @@ pub enum MdevctlCommands {
                 result is a single device, the output contains only the JSON fields necessary to \
                 recreate a config file for the device (minus attributes for listings of running \
                 devices). When the verbose option is provided, the human readable listing will \
-                include attributes for the device(s)."
+                include attributes for the device(s). Given twice, it also includes the device API \
+                and logs more, and given three times it also marks attributes whose live value \
+                differs from the definition. The 'undefined-only' option restricts the \
+                listing to active devices that have no definition, such as devices created \
+                outside of mdevctl. With 'watch', the screen is cleared and the list printed again \
+                every 'interval' seconds until the command is interrupted. With 'output=null', \
+                nothing but errors is printed and the result is only reported by the exit code, \
+                for use as a health check together with 'fail-empty'."
     )]
     List(LsmdevOptions),
     #[structopt(
         about = "List available mediated device types",
         long_about = "List available mediated device types\n\n\
-                Specifying a 'parent' lists only the types provided by the given parent device. \
-                The 'dumpjson' option provides output in machine readable JSON format."
+                Specifying a 'parent' lists only the types provided by the given parent device, \
+                without looking at any other parent. It is an error if the parent is not present \
+                or does not support mediated devices. \
+                The 'dumpjson' option provides output in machine readable JSON format, which with \
+                'parent-summary' lists the types of each parent along with a summary of its \
+                capabilities: whether it supports mediated devices, the device APIs it provides, \
+                its number of active devices, and the most instances of any one of its types that \
+                can still be created. The summary covers all types of the parent, even those \
+                hidden by other options. The \
+                'available-only' option hides types without available instances. The \
+                'missing-from' option compares the supported types with those listed in a file \
+                written by 'types --dumpjson', for example on another host, and shows only the \
+                types which are missing from the file. Types are compared by name, device API, \
+                and their human readable name and description. The 'human' option displays sizes \
+                in bytes within type descriptions in MiB or GiB. The 'count' option only prints \
+                the number of available instances of the given type of 'parent', without reading \
+                any other property of its types. With 'output=null', nothing but errors is \
+                printed and the result is only reported by the exit code."
     )]
     Types {
         #[structopt(short, long, help = "Show supported types for the specified parent")]
         parent: Option<String>,
         #[structopt(long, help = "Output mdev types list in JSON format")]
         dumpjson: bool,
+        #[structopt(
+            long,
+            requires("dumpjson"),
+            help = "Include a summary of the capabilities of each parent device in the JSON output"
+        )]
+        parent_summary: bool,
+        #[structopt(long, help = "Show only types with available instances")]
+        available_only: bool,
+        #[structopt(
+            long,
+            value_name = "jsonfile",
+            parse(from_os_str),
+            help = "Show only types which are missing from the types listed in this JSON file"
+        )]
+        missing_from: Option<PathBuf>,
+        #[structopt(
+            long,
+            conflicts_with("dumpjson"),
+            help = "Show sizes in type descriptions in human readable units"
+        )]
+        human: bool,
+        #[structopt(
+            long,
+            value_name = "type",
+            requires("parent"),
+            conflicts_with_all(&["dumpjson", "available-only", "missing-from", "human"]),
+            help = "Only print the number of available instances of this type of the parent"
+        )]
+        count: Option<String>,
+        #[structopt(
+            long,
+            value_name = "mode",
+            possible_values(&["null"]),
+            help = "With 'null', print nothing and report the result only by the exit code"
+        )]
+        output: Option<String>,
+    },
+    #[structopt(
+        about = "Convert device definitions stored in a legacy format",
+        long_about = "Convert device definitions stored in a legacy format\n\n\
+                Rewrites definitions that are stored in a legacy format in the current format. \
+                These are files named by a UUID directly in the configuration directory, which \
+                name their parent device in a 'parent' field, and definitions wrapped in an \
+                object keyed by their UUID. A legacy file is removed once its definition is \
+                rewritten. With 'dry-run', the legacy files are only listed along with the files \
+                they would be rewritten to."
+    )]
+    Migrate {
+        #[structopt(
+            long,
+            help = "List the definitions that would be converted without converting them"
+        )]
+        dry_run: bool,
+    },
+    #[structopt(
+        about = "Remove the definitions of devices whose parent is not present",
+        long_about = "Remove the definitions of devices whose parent is not present\n\n\
+                Undefines every defined device whose parent device is not present on the \
+                system, for example after the hardware was removed, and lists the devices that \
+                were undefined. Definitions of devices whose parent is present are left \
+                untouched. The number of devices to remove and some of their UUIDs are shown \
+                and confirmation is asked for, unless 'yes' is given, which is required if the \
+                standard input is not a terminal. With 'dry-run', the devices are only listed."
+    )]
+    Prune {
+        #[structopt(
+            long,
+            help = "List the devices that would be undefined without undefining them"
+        )]
+        dry_run: bool,
+        #[structopt(
+            short,
+            long,
+            conflicts_with("dry-run"),
+            help = "Undefine the devices without asking for confirmation"
+        )]
+        yes: bool,
+    },
+    #[structopt(
+        about = "Check whether a defined mediated device can be started",
+        long_about = "Check whether a defined mediated device can be started\n\n\
+                Checks that the parent device is present and supports the device type, that an \
+                instance of the type is available, and that the attributes of the definition are \
+                valid, without starting the device. Each problem found is printed and the command \
+                fails if there are any. If a UUID exists for multiple parents, the parent must be \
+                specified. With 'output=null', the problems are not printed and the result is \
+                only reported by the exit code."
+    )]
+    Verify {
+        #[structopt(short, long, help = "UUID of the device to check")]
+        uuid: Uuid,
+        #[structopt(short, long, help = "Parent of the device to check")]
+        parent: Option<String>,
+        #[structopt(
+            long,
+            value_name = "mode",
+            possible_values(&["null"]),
+            help = "With 'null', print nothing and report the result only by the exit code"
+        )]
+        output: Option<String>,
+    },
+    #[structopt(
+        about = "Wait for a mediated device to become active",
+        long_about = "Wait for a mediated device to become active\n\n\
+                Waits until the device with the given UUID appears, and if 'attr' is given, until \
+                the device provides that attribute. Exits with an error if this doesn't happen \
+                within 'timeout'."
+    )]
+    Wait {
+        #[structopt(short, long, help = "UUID of the device to wait for")]
+        uuid: Uuid,
+        #[structopt(
+            short,
+            long,
+            default_value = "30",
+            parse(try_from_str = parse_seconds),
+            help = "How long to wait, in seconds, with an optional 's' suffix"
+        )]
+        timeout: Duration,
+        #[structopt(long, help = "Also wait until the device provides this attribute")]
+        attr: Option<String>,
     },
     #[structopt(setting = structopt::clap::AppSettings::Hidden)]
     StartParentMdevs { parent: String },
 }
+
+/// parse a number of seconds such as "30" or "30s" into a Duration
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.strip_suffix('s')
+        .unwrap_or(s)
+        .parse::<u64>()
+        .map(Duration::from_secs)
+        .map_err(|_| format!("Invalid number of seconds '{}'", s))
+}
@@ -2,6 +2,57 @@
 
 use std::path::{Path, PathBuf};
 
+/// Names of the sysfs files and directories used to create, remove and enumerate mediated devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SysfsNames {
+    /// Directory of a parent device which contains one directory per supported type
+    pub supported_types: &'static str,
+    /// File of a supported type to which a uuid is written to create a device
+    pub create: &'static str,
+    /// File of a supported type containing the number of devices that can still be created
+    pub available_instances: &'static str,
+    /// File of a device to which "1" is written to remove the device
+    pub remove: &'static str,
+}
+
+/// The sysfs names used by the kernel's mediated device framework.
+pub const SYSFS_NAMES: SysfsNames = SysfsNames {
+    supported_types: "mdev_supported_types",
+    create: "create",
+    available_instances: "available_instances",
+    remove: "remove",
+};
+
+/// The layout of device definitions below [`Environment::persist_base`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PersistLayout {
+    /// One file per device, named by uuid, in a directory per parent
+    PerDevice,
+    /// One file per parent, named `<parent>.json`, containing a json object which maps the uuid of
+    /// each device to its definition
+    PerParent,
+}
+
+/// How a value is terminated when it is written to a device attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttrNewline {
+    /// Append a newline unless the value already ends with one, like `echo` does
+    Append,
+    /// Write the value exactly as given
+    Raw,
+}
+
+impl AttrNewline {
+    /// The policy for attribute `attr` given a list of attributes which are written raw, where `*`
+    /// matches any attribute
+    pub fn for_attr(raw_attrs: &[String], attr: &str) -> AttrNewline {
+        match raw_attrs.iter().any(|a| a == attr || a == "*") {
+            true => AttrNewline::Raw,
+            false => AttrNewline::Append,
+        }
+    }
+}
+
 /// A trait which provides filesystem paths for certain system resources.
 ///
 /// The main purpose of this trait is to enable testability of the mdevctl commands by abstracting
@@ -21,12 +72,75 @@ pub trait Environment {
     fn parent_base(&self) -> PathBuf {
         self.root().join("sys/class/mdev_bus")
     }
+
+    /// The directory holding the lock files which serialize operations on a device between
+    /// processes.
+    fn lock_base(&self) -> PathBuf {
+        self.root().join("run/mdevctl")
+    }
+
+    /// How device definitions are stored below `persist_base`.
+    fn persist_layout(&self) -> PersistLayout {
+        PersistLayout::PerDevice
+    }
+
+    /// The names of the sysfs control files and directories within `parent_base` and `mdev_base`.
+    fn sysfs_names(&self) -> SysfsNames {
+        SYSFS_NAMES
+    }
+
+    /// The maximum length in bytes of a value written to a device attribute. sysfs accepts at most
+    /// one page per write.
+    fn max_attr_len(&self) -> usize {
+        4096
+    }
+
+    /// How values written to the device attribute `attr` are terminated. Many drivers parse values
+    /// the way they are written by `echo`, so a newline is appended by default.
+    fn attr_newline(&self, _attr: &str) -> AttrNewline {
+        AttrNewline::Append
+    }
+
+    /// The permissions applied to a per-parent directory created to hold device definitions.
+    fn config_dir_mode(&self) -> u32 {
+        0o755
+    }
+
+    /// The permissions applied to device definition files when they are written.
+    fn config_file_mode(&self) -> u32 {
+        0o644
+    }
+
+    /// Whether this environment is read-only. When true, any operation that would create or remove
+    /// a device, write a sysfs attribute, or modify a persistent definition fails immediately
+    /// without touching the filesystem. Read operations are unaffected.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Whether `${attribute}` references in attribute values are replaced by the values of
+    /// attributes written earlier. When false, values are written exactly as defined.
+    fn interpolate_attrs(&self) -> bool {
+        false
+    }
 }
 
 /// A default implementation of the Environment trait which uses '/' as the filesystem root.
+///
+/// Setting the `MDEVCTL_READ_ONLY` environment variable to a positive integer puts the environment
+/// into read-only mode (see [`Environment::read_only`]). Setting `MDEVCTL_AGGREGATE_CONFIG` to a
+/// positive integer stores definitions in one file per parent (see [`PersistLayout::PerParent`]).
+/// `MDEVCTL_RAW_ATTRS` is a comma-separated list of attributes, or `*` for all, to which values are
+/// written without appending a newline (see [`Environment::attr_newline`]). Setting
+/// `MDEVCTL_INTERPOLATE_ATTRS` to a positive integer enables references between attribute values
+/// (see [`Environment::interpolate_attrs`]).
 #[derive(Debug)]
 pub struct DefaultEnvironment {
     rootpath: PathBuf,
+    read_only: bool,
+    persist_layout: PersistLayout,
+    raw_attrs: Vec<String>,
+    interpolate_attrs: bool,
 }
 
 impl std::fmt::Debug for &dyn Environment {
@@ -35,6 +149,9 @@ impl std::fmt::Debug for &dyn Environment {
             .field("mdev_base", &self.mdev_base())
             .field("persist_base", &self.persist_base())
             .field("parent_base", &self.parent_base())
+            .field("lock_base", &self.lock_base())
+            .field("read_only", &self.read_only())
+            .field("persist_layout", &self.persist_layout())
             .finish()
     }
 }
@@ -43,12 +160,51 @@ impl Environment for DefaultEnvironment {
     fn root(&self) -> &Path {
         self.rootpath.as_path()
     }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn persist_layout(&self) -> PersistLayout {
+        self.persist_layout
+    }
+
+    fn attr_newline(&self, attr: &str) -> AttrNewline {
+        AttrNewline::for_attr(&self.raw_attrs, attr)
+    }
+
+    fn interpolate_attrs(&self) -> bool {
+        self.interpolate_attrs
+    }
+}
+
+/// Whether the environment variable `name` is set to a positive integer
+pub(crate) fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .ok()
+        .and_then(|s| s.trim().parse::<i32>().ok())
+        .is_some_and(|n| n > 0)
 }
 
 impl DefaultEnvironment {
     pub fn new() -> DefaultEnvironment {
+        let persist_layout = match env_flag("MDEVCTL_AGGREGATE_CONFIG") {
+            true => PersistLayout::PerParent,
+            false => PersistLayout::PerDevice,
+        };
         DefaultEnvironment {
             rootpath: PathBuf::from("/"),
+            read_only: env_flag("MDEVCTL_READ_ONLY"),
+            persist_layout,
+            raw_attrs: std::env::var("MDEVCTL_RAW_ATTRS")
+                .map(|s| {
+                    s.split(',')
+                        .map(|a| a.trim().to_string())
+                        .filter(|a| !a.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            interpolate_attrs: env_flag("MDEVCTL_INTERPOLATE_ATTRS"),
         }
     }
 }
@@ -6,3 +6,25 @@ pub fn logger() -> env_logger::Builder {
         .write_style("MDEVCTL_LOG_STYLE");
     env_logger::Builder::from_env(env)
 }
+
+/// Get the log level for commands which report their result only by the exit code, which shows
+/// nothing but errors. The level set in the environment takes precedence.
+pub fn silent_level() -> Option<log::LevelFilter> {
+    match std::env::var_os("MDEVCTL_LOG") {
+        Some(_) => None,
+        None => Some(log::LevelFilter::Error),
+    }
+}
+
+/// Get the log level for listing with the given number of `-v` options: info for `-vv` and debug
+/// for `-vvv` or more. The level set in the environment takes precedence.
+pub fn verbosity_level(verbose: u8) -> Option<log::LevelFilter> {
+    if std::env::var_os("MDEVCTL_LOG").is_some() {
+        return None;
+    }
+    match verbose {
+        0 | 1 => None,
+        2 => Some(log::LevelFilter::Info),
+        _ => Some(log::LevelFilter::Debug),
+    }
+}
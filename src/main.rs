@@ -7,18 +7,20 @@
 
 use anyhow::{anyhow, ensure, Context, Result};
 use log::{debug, warn};
+use regex::Regex;
 use std::cmp::Ordering;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 use structopt::StructOpt;
 use uuid::Uuid;
 
 use crate::cli::{LsmdevOptions, MdevctlCommands};
-use crate::environment::{DefaultEnvironment, Environment};
-use crate::logger::logger;
+use crate::environment::{env_flag, DefaultEnvironment, Environment, PersistLayout};
+use crate::logger::{logger, silent_level, verbosity_level};
 use crate::mdev::*;
 
 mod cli;
@@ -26,16 +28,35 @@ mod environment;
 mod logger;
 mod mdev;
 
+#[cfg(test)]
+mod testenv;
 #[cfg(test)]
 mod tests;
 
+// interval at which the wait command checks whether the device is available
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// number of uuids shown when asking for confirmation of an operation on many devices
+const CONFIRM_SAMPLE_SIZE: usize = 5;
+// moves the cursor to the top left corner of the terminal and clears it
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+/// Render an error and the chain of its causes as a json object of the form
+/// `{"error": "...", "cause": ["...", ...]}`, with the outermost context first
+fn error_to_json(err: &anyhow::Error) -> serde_json::Value {
+    let cause: Vec<String> = err.chain().skip(1).map(|e| e.to_string()).collect();
+    serde_json::json!({
+        "error": err.to_string(),
+        "cause": cause,
+    })
+}
+
 /// Format a map of mediated devices into a json string
-fn format_json(devices: BTreeMap<String, Vec<MDev>>) -> Result<String> {
+fn format_json(devices: BTreeMap<String, Vec<MDev>>, include_state: bool) -> Result<String> {
     let mut parents = serde_json::map::Map::new();
     for (parentname, children) in devices {
         let mut childrenarray = Vec::new();
         for child in children {
-            childrenarray.push(child.to_json(true)?);
+            childrenarray.push(child.to_json(true, false, include_state)?);
         }
         parents.insert(parentname, childrenarray.into());
     }
@@ -47,6 +68,21 @@ fn format_json(devices: BTreeMap<String, Vec<MDev>>) -> Result<String> {
     serde_json::to_string_pretty(&jsonval).map_err(|_e| anyhow!("Unable to serialize json"))
 }
 
+/// Determine the parent of a device specified via json. A parent given on the command line takes
+/// precedence over a "parent" field within the json itself.
+fn json_parent(parent: Option<String>, json: &serde_json::Value) -> Option<String> {
+    let jsonparent = json["parent"].as_str().map(|p| p.to_string());
+    match (parent, jsonparent) {
+        (Some(p), Some(jp)) => {
+            if p != jp {
+                warn!("Parent '{}' overrides parent '{}' specified in json", p, jp);
+            }
+            Some(p)
+        }
+        (p, jp) => p.or(jp),
+    }
+}
+
 /// convert 'define' command arguments into a MDev struct
 fn define_command_helper(
     env: &dyn Environment,
@@ -55,7 +91,7 @@ fn define_command_helper(
     parent: Option<String>,
     mdev_type: Option<String>,
     jsonfile: Option<PathBuf>,
-) -> Result<MDev> {
+) -> Result<MDev<'_>> {
     let uuid_provided = uuid.is_some();
     let uuid = uuid.unwrap_or_else(Uuid::new_v4);
     let mut dev = MDev::new(env, uuid);
@@ -71,7 +107,11 @@ fn define_command_helper(
             ));
         }
 
-        let parent = parent
+        let filecontents = fs::read_to_string(&jsonfile)
+            .with_context(|| format!("Unable to read jsonfile {:?}", jsonfile))?;
+        let jsonval = serde_json::from_str(&filecontents)?;
+
+        let parent = json_parent(parent, &jsonval)
             .ok_or_else(|| anyhow!("Parent device required to define device via {:?}", jsonfile))?;
 
         let devs = defined_devices(env, Some(&uuid), Some(&parent))?;
@@ -79,13 +119,10 @@ fn define_command_helper(
             return Err(anyhow!(
                 "Cowardly refusing to overwrite existing config for {}/{}",
                 parent,
-                uuid.to_hyphenated().to_string()
+                uuid.to_hyphenated()
             ));
         }
 
-        let filecontents = fs::read_to_string(&jsonfile)
-            .with_context(|| format!("Unable to read jsonfile {:?}", jsonfile))?;
-        let jsonval = serde_json::from_str(&filecontents)?;
         dev.load_from_json(parent, &jsonval)?;
     } else {
         if uuid_provided {
@@ -113,7 +150,7 @@ fn define_command_helper(
         if dev.is_defined() {
             return Err(anyhow!(
                 "Device {} on {} already defined",
-                dev.uuid.to_hyphenated().to_string(),
+                dev.uuid.to_hyphenated(),
                 dev.parent()?
             ));
         }
@@ -123,6 +160,7 @@ fn define_command_helper(
 }
 
 /// Implementation of the `mdevctl define` command
+#[allow(clippy::too_many_arguments)]
 fn define_command(
     env: &dyn Environment,
     uuid: Option<Uuid>,
@@ -130,17 +168,98 @@ fn define_command(
     parent: Option<String>,
     mdev_type: Option<String>,
     jsonfile: Option<PathBuf>,
+    start: bool,
+    keep_on_failure: bool,
+    dedup: bool,
+    print_json: bool,
 ) -> Result<()> {
     debug!("Defining mdev {:?}", uuid);
 
-    let dev = define_command_helper(env, uuid, auto, parent, mdev_type, jsonfile)?;
-    dev.define().map(|_| {
-        if uuid.is_none() {
-            println!("{}", dev.uuid.to_hyphenated());
+    let mut dev = define_command_helper(env, uuid, auto, parent, mdev_type, jsonfile)?;
+    if dedup {
+        report_dedup(&mut dev);
+    }
+    for problem in dev.api_problems() {
+        warn!(
+            "Device {} will fail to start: {}",
+            dev.uuid.to_hyphenated(),
+            problem
+        );
+    }
+    dev.define()?;
+    let result = match start {
+        true => dev.start(false, None),
+        false => Ok(()),
+    };
+    if result.is_err() && !keep_on_failure {
+        debug!("Removing definition of {:?} after failed start", dev.uuid);
+        if let Err(e) = dev.undefine() {
+            warn!("Failed to remove definition after failed start: {}", e);
         }
+    } else if let Some(output) = success_output(&dev, result.is_ok() && print_json, uuid.is_none())?
+    {
+        println!("{}", output);
+    }
+    result
+}
+
+/// Remove the duplicate attributes of `dev` and report how many there were
+fn report_dedup(dev: &mut MDev) {
+    let n = dev.dedup_attributes();
+    eprintln!(
+        "Removed {} duplicate attribute(s) from device {}",
+        n,
+        dev.uuid.to_hyphenated()
+    );
+}
+
+/// Get the output of a command that succeeded in creating or changing `dev`: the device in json
+/// format if `print_json` is set, otherwise its uuid if `print_uuid` is set.
+fn success_output(dev: &MDev, print_json: bool, print_uuid: bool) -> Result<Option<String>> {
+    if print_json {
+        let json = serde_json::to_string_pretty(&dev.to_json(true, false, false)?)
+            .map_err(|_e| anyhow!("Unable to serialize json"))?;
+        return Ok(Some(json));
+    }
+    Ok(match print_uuid {
+        true => Some(dev.uuid.to_hyphenated().to_string()),
+        false => None,
     })
 }
 
+/// convert 'adopt' command arguments into a MDev struct
+fn adopt_command_helper(
+    env: &dyn Environment,
+    uuid: Uuid,
+    auto: bool,
+    force: bool,
+) -> Result<MDev<'_>> {
+    let mut dev = MDev::new(env, uuid);
+    dev.load_from_sysfs()?;
+    if !dev.active {
+        return Err(anyhow!("Device {} is not active", uuid.to_hyphenated()));
+    }
+
+    if dev.is_defined() && !force {
+        return Err(anyhow!(
+            "Device {} on {} already defined",
+            uuid.to_hyphenated(),
+            dev.parent()?
+        ));
+    }
+
+    dev.autostart = auto;
+    dev.attrs = dev.sysfs_attrs()?;
+    Ok(dev)
+}
+
+/// Implementation of the `mdevctl adopt` command
+fn adopt_command(env: &dyn Environment, uuid: Uuid, auto: bool, force: bool) -> Result<()> {
+    debug!("Adopting mdev {:?}", uuid);
+    let dev = adopt_command_helper(env, uuid, auto, force)?;
+    dev.define()
+}
+
 /// Implementation of the `mdevctl undefine` command
 fn undefine_command(env: &dyn Environment, uuid: Uuid, parent: Option<String>) -> Result<()> {
     debug!("Undefining mdev {:?}", uuid);
@@ -156,6 +275,387 @@ fn undefine_command(env: &dyn Environment, uuid: Uuid, parent: Option<String>) -
     Ok(())
 }
 
+/// Ask for confirmation before performing `action` on each of the devices `uuids`, by showing the
+/// number of devices and some of their uuids on `output` and reading the answer from `input`. The
+/// question is skipped if `yes` is set, and the operation refused if it can't be asked because the
+/// input isn't `interactive`.
+fn confirm_bulk(
+    action: &str,
+    uuids: &[Uuid],
+    yes: bool,
+    interactive: bool,
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+) -> Result<()> {
+    if yes {
+        return Ok(());
+    }
+    ensure!(
+        interactive,
+        "Refusing to {} {} device(s) without confirmation, use --yes to proceed",
+        action,
+        uuids.len()
+    );
+
+    let mut sample: Vec<String> = uuids
+        .iter()
+        .take(CONFIRM_SAMPLE_SIZE)
+        .map(|u| u.to_hyphenated().to_string())
+        .collect();
+    if uuids.len() > CONFIRM_SAMPLE_SIZE {
+        sample.push(format!("and {} more", uuids.len() - CONFIRM_SAMPLE_SIZE));
+    }
+    write!(
+        output,
+        "About to {} {} device(s): {}\nContinue? [y/N] ",
+        action,
+        uuids.len(),
+        sample.join(", ")
+    )?;
+    output.flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(anyhow!("Aborted")),
+    }
+}
+
+/// Implementation of the `mdevctl undefine` command when selecting devices by filter rather than
+/// uuid
+fn undefine_matching_command(
+    env: &dyn Environment,
+    parent: Option<String>,
+    mdev_type: Option<String>,
+    uuid_prefix: Option<String>,
+    dry_run: bool,
+    yes: bool,
+) -> Result<()> {
+    let parent = parent.as_deref();
+    let mdev_type = mdev_type.as_deref();
+    let uuid_prefix = uuid_prefix.as_deref();
+    let devs = matching_definitions(env, parent, mdev_type, uuid_prefix)?;
+    if dry_run {
+        for dev in devs {
+            println!(
+                "{} {} {}",
+                dev.uuid.to_hyphenated(),
+                dev.parent()?,
+                dev.mdev_type()?
+            );
+        }
+        return Ok(());
+    }
+    if devs.is_empty() {
+        return Err(anyhow!("No devices match the specified filters"));
+    }
+    let uuids: Vec<Uuid> = devs.iter().map(|d| d.uuid).collect();
+    let stdin = std::io::stdin();
+    confirm_bulk(
+        "undefine",
+        &uuids,
+        yes,
+        stdin.is_terminal(),
+        &mut stdin.lock(),
+        &mut std::io::stderr(),
+    )?;
+
+    // undefine exactly the devices that were confirmed rather than searching again
+    let mut failed = 0;
+    for (uuid, result) in undefine_devices(env, devs) {
+        if let Err(e) = result {
+            warn!("Failed to undefine {}: {:#}", uuid.to_hyphenated(), e);
+            failed += 1;
+        }
+    }
+    ensure!(failed == 0, "Failed to undefine {} device(s)", failed);
+    Ok(())
+}
+
+/// Find device definitions stored in a legacy format: files named by a uuid directly in the
+/// persistent configuration directory, which name their parent in a "parent" field, and, with
+/// [`PersistLayout::PerDevice`], definitions wrapped in an object keyed by their uuid. Each device
+/// is returned along with the file it was read from. Files which can't be read are skipped.
+fn legacy_definitions(env: &dyn Environment) -> Result<Vec<(PathBuf, MDev<'_>)>> {
+    let mut candidates = Vec::new();
+    for entry in env.persist_base().read_dir()? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            if let Ok(u) = Uuid::parse_str(&entry.file_name().to_string_lossy()) {
+                candidates.push((entry.path(), u, None));
+            }
+        } else if metadata.is_dir() && env.persist_layout() == PersistLayout::PerDevice {
+            let parentname = entry.file_name().to_string_lossy().to_string();
+            for child in entry.path().read_dir()? {
+                let child = child?;
+                if let Ok(u) = Uuid::parse_str(&child.file_name().to_string_lossy()) {
+                    candidates.push((child.path(), u, Some(parentname.clone())));
+                }
+            }
+        }
+    }
+    candidates.sort();
+
+    let mut legacy = Vec::new();
+    for (path, uuid, parent) in candidates {
+        let val = match fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| Ok(serde_json::from_str(&contents)?))
+        {
+            Ok(val) => val,
+            Err(e) => {
+                warn!("Skipping definition {:?}: {:#}", path, e);
+                continue;
+            }
+        };
+        let (val, wrapped) = unwrap_legacy_json(&uuid, val);
+        if parent.is_some() && !wrapped {
+            continue;
+        }
+        let parent = match json_parent(parent, &val) {
+            Some(p) => p,
+            None => {
+                warn!("Skipping definition {:?}: no parent device", path);
+                continue;
+            }
+        };
+        let mut dev = MDev::new(env, uuid);
+        if let Err(e) = dev.load_from_json(parent, &val) {
+            warn!("Skipping definition {:?}: {:#}", path, e);
+            continue;
+        }
+        legacy.push((path, dev));
+    }
+    Ok(legacy)
+}
+
+/// Implementation of the `mdevctl migrate` command
+fn migrate_command(env: &dyn Environment, dry_run: bool) -> Result<()> {
+    let mut failed = 0;
+    for (path, dev) in legacy_definitions(env)? {
+        let newpath = dev.persist_path().unwrap();
+        if dry_run {
+            println!("{} -> {}", path.display(), newpath.display());
+            continue;
+        }
+        debug!("Migrating {:?} to {:?}", path, newpath);
+        let result = match path != newpath && dev.is_defined() {
+            true => Err(anyhow!(
+                "device {} is already defined for parent {}",
+                dev.uuid.to_hyphenated(),
+                dev.parent()?
+            )),
+            false => dev.write_config().and_then(|_| match path != newpath {
+                true => fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove file {:?}", path)),
+                false => Ok(()),
+            }),
+        };
+        if let Err(e) = result {
+            warn!("Failed to migrate {:?}: {:#}", path, e);
+            failed += 1;
+        }
+    }
+    ensure!(failed == 0, "Failed to migrate {} definition(s)", failed);
+    Ok(())
+}
+
+/// Get the defined devices matching all of the given filters
+fn matching_definitions<'a>(
+    env: &'a dyn Environment,
+    parent: Option<&str>,
+    mdev_type: Option<&str>,
+    uuid_prefix: Option<&str>,
+) -> Result<Vec<MDev<'a>>> {
+    let parent = parent.map(|p| p.to_string());
+    let uuid_prefix = uuid_prefix.map(|p| p.to_lowercase());
+    let mut devs = Vec::new();
+    for dev in iter_defined_devices(env, None, parent.as_ref())? {
+        let dev = dev?;
+        if mdev_type.is_some_and(|t| dev.mdev_type.as_deref() != Some(t)) {
+            debug!("Ignoring device {} because it doesn't match type", dev.uuid);
+            continue;
+        }
+        if uuid_prefix
+            .as_ref()
+            .is_some_and(|p| !dev.uuid.to_hyphenated().to_string().starts_with(p.as_str()))
+        {
+            debug!(
+                "Ignoring device {} because it doesn't match prefix",
+                dev.uuid
+            );
+            continue;
+        }
+        devs.push(dev);
+    }
+    Ok(devs)
+}
+
+/// Undefine each of the given devices, returning the result for each device. Parent directories
+/// that are left empty are removed.
+fn undefine_devices(env: &dyn Environment, devs: Vec<MDev>) -> Vec<(Uuid, Result<()>)> {
+    let mut results = Vec::new();
+    let mut parents = BTreeSet::new();
+    for mut dev in devs {
+        debug!("Undefining mdev {:?}", dev.uuid);
+        let result = dev.undefine();
+        if let (Ok(()), Some(p)) = (&result, dev.parent.as_ref()) {
+            parents.insert(p.clone());
+        }
+        results.push((dev.uuid, result));
+    }
+    remove_empty_parent_dirs(env, parents);
+    results
+}
+
+/// Remove the directories of `parents` in the persistent configuration directory if they were left
+/// empty by undefining devices.
+fn remove_empty_parent_dirs(env: &dyn Environment, parents: BTreeSet<String>) {
+    for p in parents {
+        let dir = env.persist_base().join(p);
+        if dir.read_dir().is_ok_and(|mut d| d.next().is_none()) {
+            debug!("Removing empty parent directory {:?}", dir);
+            if let Err(e) = fs::remove_dir(&dir) {
+                warn!("Failed to remove directory {:?}: {}", dir, e);
+            }
+        }
+    }
+}
+
+/// Get the definitions of devices whose parent device is not present, such as definitions left
+/// behind by hardware which was removed from the system.
+fn orphaned_definitions(env: &dyn Environment) -> Result<Vec<MDev<'_>>> {
+    // without the class of parent devices every parent would appear to be missing
+    check_mdev_support(env)?;
+    if !env.parent_base().is_dir() {
+        return Err(anyhow!(
+            "Unable to find parent devices in {:?}",
+            env.parent_base()
+        ));
+    }
+    let mut orphans = Vec::new();
+    for dev in iter_defined_devices(env, None, None)? {
+        let dev = dev?;
+        if !env.parent_base().join(dev.parent()?).exists() {
+            orphans.push(dev);
+        }
+    }
+    Ok(orphans)
+}
+
+/// Undefine every device whose parent device is not present, after asking for confirmation as
+/// described for [`confirm_bulk`]. Returns the devices which were undefined.
+fn prune<'a>(
+    env: &'a dyn Environment,
+    yes: bool,
+    interactive: bool,
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+) -> Result<Vec<MDev<'a>>> {
+    let orphans = orphaned_definitions(env)?;
+    if orphans.is_empty() {
+        return Ok(orphans);
+    }
+    let uuids: Vec<Uuid> = orphans.iter().map(|d| d.uuid).collect();
+    confirm_bulk("prune", &uuids, yes, interactive, input, output)?;
+
+    let mut pruned = Vec::new();
+    let mut parents = BTreeSet::new();
+    let mut failed = 0;
+    for mut dev in orphans {
+        debug!("Pruning mdev {:?}", dev.uuid);
+        match dev.undefine() {
+            Ok(()) => {
+                parents.insert(dev.parent()?.clone());
+                pruned.push(dev);
+            }
+            Err(e) => {
+                warn!("Failed to undefine {}: {:#}", dev.uuid.to_hyphenated(), e);
+                failed += 1;
+            }
+        }
+    }
+    remove_empty_parent_dirs(env, parents);
+    ensure!(failed == 0, "Failed to undefine {} device(s)", failed);
+    Ok(pruned)
+}
+
+/// Implementation of the `mdevctl prune` command
+fn prune_command(env: &dyn Environment, dry_run: bool, yes: bool) -> Result<()> {
+    if dry_run {
+        for dev in orphaned_definitions(env)? {
+            println!("{} {}", dev.uuid.to_hyphenated(), dev.parent()?);
+        }
+        return Ok(());
+    }
+    let stdin = std::io::stdin();
+    let pruned = prune(
+        env,
+        yes,
+        stdin.is_terminal(),
+        &mut stdin.lock(),
+        &mut std::io::stderr(),
+    )?;
+    for dev in pruned {
+        println!(
+            "Removed definition of {} on missing parent {}",
+            dev.uuid.to_hyphenated(),
+            dev.parent()?
+        );
+    }
+    Ok(())
+}
+
+/// convert 'export' command arguments into the json definition of a single device
+fn export_command_helper(
+    env: &dyn Environment,
+    uuid: Uuid,
+    parent: Option<String>,
+    flat_attrs: bool,
+    sort_attrs: bool,
+) -> Result<String> {
+    let dev = get_defined_device(env, uuid, parent.as_ref())?;
+    let json = match sort_attrs {
+        true => dev.to_json_sorted(false, flat_attrs)?,
+        false => dev.to_json(false, flat_attrs, false)?,
+    };
+    serde_json::to_string_pretty(&json).map_err(|_e| anyhow!("Unable to serialize json"))
+}
+
+/// Implementation of the `mdevctl export` command
+fn export_command(
+    env: &dyn Environment,
+    uuid: Uuid,
+    parent: Option<String>,
+    flat_attrs: bool,
+    sort_attrs: bool,
+    null_output: bool,
+) -> Result<()> {
+    debug!("Exporting mdev {:?}", uuid);
+    let output = export_command_helper(env, uuid, parent, flat_attrs, sort_attrs)?;
+    if !null_output {
+        println!("{}", output);
+    }
+    Ok(())
+}
+
+/// Produce a unified diff between the json definitions of two devices
+fn definition_diff(old: &MDev, new: &MDev) -> Result<String> {
+    let oldjson = serde_json::to_string_pretty(&old.to_json(false, false, false)?)?;
+    let newjson = serde_json::to_string_pretty(&new.to_json(false, false, false)?)?;
+    let oldpath = old
+        .persist_path()
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_default();
+    Ok(
+        similar::TextDiff::from_lines(&format!("{}\n", oldjson), &format!("{}\n", newjson))
+            .unified_diff()
+            .header(&oldpath, &format!("{} (modified)", oldpath))
+            .to_string(),
+    )
+}
+
 /// Implementation of the `mdevctl modify` command
 #[allow(clippy::too_many_arguments)]
 fn modify_command(
@@ -165,12 +665,35 @@ fn modify_command(
     mdev_type: Option<String>,
     addattr: Option<String>,
     delattr: bool,
+    delattr_name: Option<String>,
+    all: bool,
     index: Option<u32>,
     value: Option<String>,
     auto: bool,
     manual: bool,
+    apply_now: bool,
+    dry_run: bool,
+    new_uuid: Option<Uuid>,
+    dedup: bool,
+    print_json: bool,
 ) -> Result<()> {
     let mut dev = get_defined_device(env, uuid, parent.as_ref())?;
+    let orig = dev.clone();
+    if let Some(new) = new_uuid {
+        if dev.active {
+            return Err(anyhow!(
+                "Device {} is active, stop it before changing its UUID",
+                uuid.to_hyphenated()
+            ));
+        }
+        if !defined_devices(env, Some(&new), None)?.is_empty() {
+            return Err(anyhow!(
+                "Mediated device {} is already defined",
+                new.to_hyphenated()
+            ));
+        }
+        dev.uuid = new;
+    }
     if mdev_type.is_some() {
         dev.mdev_type = mdev_type;
     }
@@ -187,30 +710,85 @@ fn modify_command(
             Some(v) => dev.add_attribute(attr, v, index)?,
         },
         None => {
-            if delattr {
+            if let Some(name) = delattr_name {
+                let n = dev.delete_attribute_by_name(&name, all)?;
+                debug!("Deleted {} attribute(s) named '{}'", n, name);
+            } else if delattr {
                 dev.delete_attribute(index)?;
             }
         }
     }
+    if dedup {
+        report_dedup(&mut dev);
+    }
+
+    if dry_run {
+        if dev.uuid != orig.uuid {
+            println!(
+                "Device {} would be renamed to {}",
+                orig.uuid.to_hyphenated(),
+                dev.uuid.to_hyphenated()
+            );
+        }
+        print!("{}", definition_diff(&orig, &dev)?);
+        return Ok(());
+    }
 
-    dev.write_config()
+    dev.define_if_changed()?;
+    if dev.uuid != orig.uuid {
+        // the definition under the new uuid is complete, so the old one can go. If it can't, drop
+        // the new one again rather than leaving two copies behind.
+        let mut old = orig.clone();
+        if let Err(e) = old.undefine() {
+            if let Err(e) = dev.undefine() {
+                warn!("Failed to remove new definition: {:#}", e);
+            }
+            return Err(e);
+        }
+    }
+
+    if apply_now && dev.active {
+        debug!("Applying changes to running device {:?}", dev.uuid);
+        for change in dev.apply_live(&orig) {
+            eprintln!("Restart required to apply change: {}", change);
+        }
+    }
+    if let Some(output) = success_output(&dev, print_json, false)? {
+        println!("{}", output);
+    }
+    Ok(())
+}
+
+/// Read the contents of the json file `fname`, or of `stdin` if `fname` is `-`.
+fn read_jsonfile(fname: &Path, stdin: &mut dyn Read) -> Result<String> {
+    let mut contents = String::new();
+    match fname.as_os_str() == "-" {
+        true => stdin
+            .read_to_string(&mut contents)
+            .context("Unable to read json from standard input")?,
+        false => fs::File::open(fname)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .with_context(|| format!("Unable to read jsonfile {:?}", fname))?,
+    };
+    Ok(contents)
 }
 
 /// convert 'start' command arguments into a MDev struct
-fn start_command_helper(
-    env: &dyn Environment,
+fn start_command_helper<'a>(
+    env: &'a dyn Environment,
     uuid: Option<Uuid>,
     parent: Option<String>,
     mdev_type: Option<String>,
     jsonfile: Option<PathBuf>,
-) -> Result<MDev> {
+    stdin: &mut dyn Read,
+) -> Result<MDev<'a>> {
     debug!("Starting device '{:?}'", uuid);
     let mut dev: Option<MDev> = None;
     match jsonfile {
         Some(fname) => {
-            let contents = fs::read_to_string(&fname)
-                .with_context(|| format!("Unable to read jsonfile {:?}", fname))?;
-            let val = serde_json::from_str(&contents)?;
+            let contents = read_jsonfile(&fname, stdin)?;
+            let val = serde_json::from_str(&contents)
+                .with_context(|| format!("Invalid json in {:?}", fname))?;
 
             if mdev_type.is_some() {
                 return Err(anyhow!(
@@ -218,7 +796,7 @@ fn start_command_helper(
                 ));
             }
 
-            let parent = parent
+            let parent = json_parent(parent, &val)
                 .ok_or_else(|| anyhow!("Parent device required to start device via json file"))?;
 
             let mut d = MDev::new(env, uuid.unwrap_or_else(Uuid::new_v4));
@@ -227,14 +805,14 @@ fn start_command_helper(
         }
         _ => {
             // if the user specified a uuid, check to see if they're referring to a defined device
-            if uuid.is_some() {
-                let devs = defined_devices(env, uuid.as_ref(), parent.as_ref())?;
+            if let Some(u) = uuid {
+                let devs = defined_devices(env, Some(&u), parent.as_ref())?;
                 let n = devs.values().flatten().count();
                 match n.cmp(&1) {
                     Ordering::Greater => {
                         return Err(anyhow!(
                             "Multiple definitions found for device {}. Please specify a parent.",
-                            uuid.unwrap().to_hyphenated().to_string()
+                            u.to_hyphenated()
                         ));
                     }
                     Ordering::Equal => {
@@ -245,7 +823,7 @@ fn start_command_helper(
                             if mdev_type.is_some() && mdev_type != d.mdev_type {
                                 return Err(anyhow!(
                                     "Device {} already exists on parent {} with type {}",
-                                    d.uuid.to_hyphenated().to_string(),
+                                    d.uuid.to_hyphenated(),
                                     d.parent().unwrap(),
                                     d.mdev_type.as_ref().unwrap()
                                 ));
@@ -260,7 +838,7 @@ fn start_command_helper(
 
             if dev.is_none() {
                 let mut d = MDev::new(env, uuid.unwrap_or_else(Uuid::new_v4));
-                d.parent = parent;
+                d.parent = parent.map(|p| resolve_parent(env, &p)).transpose()?;
                 d.mdev_type = mdev_type;
                 dev = Some(d);
             }
@@ -279,27 +857,96 @@ fn start_command_helper(
 }
 
 /// Implementation of the `mdevctl start` command
+#[allow(clippy::too_many_arguments)]
 fn start_command(
     env: &dyn Environment,
     uuid: Option<Uuid>,
     parent: Option<String>,
     mdev_type: Option<String>,
     jsonfile: Option<PathBuf>,
+    strict: bool,
+    timeout: Option<Duration>,
+    retries: u32,
+    retry_delay: Duration,
+    print_json: bool,
 ) -> Result<()> {
-    let mut dev = start_command_helper(env, uuid, parent, mdev_type, jsonfile)?;
-    dev.start().map(|_| {
-        if uuid.is_none() {
-            println!("{}", dev.uuid.to_hyphenated());
-        }
-    })
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let mut dev = start_command_helper(
+        env,
+        uuid,
+        parent,
+        mdev_type,
+        jsonfile,
+        &mut std::io::stdin(),
+    )?;
+    retry_op(retries, retry_delay, deadline, || {
+        dev.start(strict, deadline)
+    })?;
+    if let Some(output) = success_output(&dev, print_json, uuid.is_none())? {
+        println!("{}", output);
+    }
+    Ok(())
 }
 
 /// Implementation of the `mdevctl stop` command
-fn stop_command(env: &dyn Environment, uuid: Uuid) -> Result<()> {
+fn stop_command(
+    env: &dyn Environment,
+    uuid: Uuid,
+    force: bool,
+    wait: Option<Duration>,
+) -> Result<()> {
     debug!("Stopping '{}'", uuid);
     let mut dev = MDev::new(env, uuid);
     dev.load_from_sysfs()?;
-    dev.stop()
+    dev.stop(force, wait)
+}
+
+/// Implementation of the `mdevctl verify` command
+fn verify_command(
+    env: &dyn Environment,
+    uuid: Uuid,
+    parent: Option<String>,
+    null_output: bool,
+) -> Result<()> {
+    debug!("Verifying '{}'", uuid);
+    let dev = get_defined_device(env, uuid, parent.as_ref())?;
+    let problems = dev.verify()?;
+    for problem in problems.iter().filter(|_| !null_output) {
+        println!("{}", problem);
+    }
+    match problems.len() {
+        0 => Ok(()),
+        n => Err(anyhow!(
+            "Device {} cannot be started: {} problem(s) found",
+            uuid.to_hyphenated(),
+            n
+        )),
+    }
+}
+
+/// Implementation of the `mdevctl wait` command
+fn wait_command(
+    env: &dyn Environment,
+    uuid: Uuid,
+    timeout: Duration,
+    attr: Option<String>,
+) -> Result<()> {
+    debug!("Waiting for '{}'", uuid);
+    let deadline = Instant::now() + timeout;
+    let dev = MDev::new(env, uuid);
+    loop {
+        let path = dev.path();
+        if path.exists() && attr.as_ref().is_none_or(|a| path.join(a).exists()) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "Timed out waiting for device {}",
+                uuid.to_hyphenated()
+            ));
+        }
+        std::thread::sleep(WAIT_POLL_INTERVAL);
+    }
 }
 
 /// convenience function to lookup a defined device by uuid and parent
@@ -310,39 +957,39 @@ fn get_defined_device<'a>(
 ) -> Result<MDev<'a>> {
     let devs = defined_devices(env, Some(&uuid), parent)?;
     if devs.is_empty() {
-        return match parent {
+        match parent {
             None => Err(anyhow!(
                 "Mediated device {} is not defined",
-                uuid.to_hyphenated().to_string()
+                uuid.to_hyphenated()
             )),
             Some(p) => Err(anyhow!(
                 "Mediated device {}/{} is not defined",
                 p,
-                uuid.to_hyphenated().to_string()
+                uuid.to_hyphenated()
             )),
-        };
+        }
     } else if devs.len() > 1 {
-        return match parent {
+        match parent {
             None => Err(anyhow!(
                 "Multiple definitions found for {}, specify a parent",
-                uuid.to_hyphenated().to_string()
+                uuid.to_hyphenated()
             )),
             Some(p) => Err(anyhow!(
                 "Multiple definitions found for {}/{}",
                 p,
-                uuid.to_hyphenated().to_string()
+                uuid.to_hyphenated()
             )),
-        };
+        }
     } else {
         let (parent, children) = devs.iter().next().unwrap();
         if children.len() > 1 {
             return Err(anyhow!(
                 "Multiple definitions found for {}/{}",
                 parent,
-                uuid.to_hyphenated().to_string()
+                uuid.to_hyphenated()
             ));
         }
-        return Ok(children.get(0).unwrap().clone());
+        Ok(children.first().unwrap().clone())
     }
 }
 
@@ -353,155 +1000,306 @@ fn defined_devices<'a>(
     parent: Option<&String>,
 ) -> Result<BTreeMap<String, Vec<MDev<'a>>>> {
     let mut devices: BTreeMap<String, Vec<MDev>> = BTreeMap::new();
+    for dev in iter_defined_devices(env, uuid, parent)? {
+        let dev = dev?;
+        devices.entry(dev.parent()?.clone()).or_default().push(dev);
+    }
+    Ok(devices)
+}
+
+/// Get the metadata of a directory entry, or None if the entry was removed after the directory was
+/// read, such as a definition undefined by another process during enumeration.
+fn entry_metadata(entry: &fs::DirEntry) -> Result<Option<fs::Metadata>> {
+    match entry.metadata() {
+        Ok(m) => Ok(Some(m)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("{:?} was removed during enumeration", entry.path());
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Get an iterator over all defined devices, optionally filtered by uuid and parent. The
+/// persistent configuration directory is walked up front, but each device definition is only
+/// loaded when the iterator reaches it. With [`PersistLayout::PerParent`], the file of each parent
+/// is read up front to find its devices. Devices are yielded in order of parent and uuid. A
+/// definition which is removed while enumerating, for example because another process undefined
+/// it concurrently, is skipped.
+fn iter_defined_devices<'a>(
+    env: &'a dyn Environment,
+    uuid: Option<&Uuid>,
+    parent: Option<&String>,
+) -> Result<impl Iterator<Item = Result<MDev<'a>>>> {
+    let mut files = Vec::new();
     debug!(
         "Looking up defined mdevs: uuid={:?}, parent={:?}",
         uuid, parent
     );
-    for parentpath in env.persist_base().read_dir()? {
-        let parentpath = parentpath?;
-        let parentname = parentpath.file_name();
-        let parentname = parentname.to_str().unwrap();
-        if (parent.is_some() && parent.unwrap() != parentname) || !parentpath.metadata()?.is_dir() {
-            debug!("Ignoring child devices for parent {}", parentname);
-            continue;
-        }
-
-        let mut childdevices = Vec::new();
+    let mut found = Vec::new();
+    match env.persist_layout() {
+        PersistLayout::PerDevice => {
+            for parentpath in env.persist_base().read_dir()? {
+                let parentpath = parentpath?;
+                let parentname = parentpath.file_name();
+                let parentname = parentname.to_str().unwrap();
+                let metadata = match entry_metadata(&parentpath)? {
+                    Some(m) => m,
+                    None => continue,
+                };
+                if metadata.is_file() && Uuid::parse_str(parentname).is_ok() {
+                    warn!(
+                        "Ignoring legacy definition {:?}, use 'mdevctl migrate' to convert it",
+                        parentpath.path()
+                    );
+                    continue;
+                }
+                if parent.is_some_and(|p| p != parentname) || !metadata.is_dir() {
+                    debug!("Ignoring child devices for parent {}", parentname);
+                    continue;
+                }
 
-        for child in parentpath.path().read_dir()? {
-            let child = child?;
-            if !child.metadata()?.is_file() {
-                continue;
+                let children = match parentpath.path().read_dir() {
+                    Ok(children) => children,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                for child in children {
+                    let child = child?;
+                    if !entry_metadata(&child)?.is_some_and(|m| m.is_file()) {
+                        continue;
+                    }
+                    let basename = child.file_name().to_str().unwrap().to_string();
+                    found.push((parentname.to_string(), basename));
+                }
             }
+        }
+        PersistLayout::PerParent => {
+            for entry in env.persist_base().read_dir()? {
+                let entry = entry?;
+                let path = entry.path();
+                let parentname = match path.extension().and_then(|e| e.to_str()) {
+                    Some("json") if entry_metadata(&entry)?.is_some_and(|m| m.is_file()) => {
+                        path.file_stem().unwrap().to_str().unwrap().to_string()
+                    }
+                    _ => continue,
+                };
+                if parent.is_some_and(|p| *p != parentname) {
+                    debug!("Ignoring child devices for parent {}", parentname);
+                    continue;
+                }
 
-            let path = child.path();
-            let basename = path.file_name().unwrap().to_str().unwrap();
-            let u = Uuid::parse_str(basename);
-            if u.is_err() {
-                warn!("Can't determine uuid for file '{}'", basename);
-                continue;
+                for key in read_aggregate(&path)?.keys() {
+                    found.push((parentname.clone(), key.clone()));
+                }
             }
-            let u = u.unwrap();
+        }
+    }
 
-            debug!("found mdev {:?}", u);
-            if uuid.is_some() && uuid != Some(&u) {
+    for (parentname, basename) in found {
+        let u = Uuid::parse_str(&basename);
+        if u.is_err() {
+            warn!("Can't determine uuid for file '{}'", basename);
+            continue;
+        }
+        let u = u.unwrap();
+
+        debug!("found mdev {:?}", u);
+        if let Some(filter) = uuid.filter(|filter| **filter != u) {
+            debug!(
+                "Ignoring device {} because it doesn't match uuid {}",
+                u, filter
+            );
+            continue;
+        }
+
+        files.push((parentname, u));
+    }
+    files.sort();
+
+    Ok(files.into_iter().filter_map(move |(parentname, u)| {
+        let mut dev = MDev::new(env, u);
+        dev.parent = Some(parentname);
+        if let Err(e) = dev.load_definition() {
+            if !dev.is_defined() {
                 debug!(
-                    "Ignoring device {} because it doesn't match uuid {}",
-                    u,
-                    uuid.unwrap()
+                    "Skipping device {} which was undefined during enumeration",
+                    u
                 );
-                continue;
+                return None;
             }
-
-            let mut f = fs::File::open(path)?;
-            let mut contents = String::new();
-            f.read_to_string(&mut contents)?;
-            let val = serde_json::from_str(&contents)?;
-            let mut dev = MDev::new(env, u);
-            dev.load_from_json(parentname.to_string(), &val)?;
-            dev.load_from_sysfs()?;
-
-            childdevices.push(dev);
-        }
-        if !childdevices.is_empty() {
-            devices.insert(parentname.to_string(), childdevices);
+            return Some(Err(e));
         }
-    }
-    Ok(devices)
+        Some(dev.load_from_sysfs().map(|_| dev))
+    }))
 }
 
 /// Implementation of the `mdevctl list` command
+#[allow(clippy::too_many_arguments)]
 fn list_command(
     env: &dyn Environment,
     defined: bool,
     dumpjson: bool,
-    verbose: bool,
+    include_state: bool,
+    verbose: u8,
+    diff_attrs: bool,
     uuid: Option<Uuid>,
     parent: Option<String>,
+    parent_regex: Option<String>,
+    match_attr: Option<String>,
+    undefined_only: bool,
+    output_dir: Option<PathBuf>,
+    timeout: Option<u64>,
+    fail_empty: bool,
+    watch_interval: Option<Duration>,
+    null_output: bool,
+    out: &mut dyn Write,
 ) -> Result<()> {
-    let output = list_command_helper(env, defined, dumpjson, verbose, uuid, parent)?;
-    println!("{}", output);
+    // the result is only reported by the exit code
+    let mut sink = std::io::sink();
+    let out: &mut dyn Write = match null_output {
+        true => &mut sink,
+        false => out,
+    };
+    let deadline = timeout.map(|secs| Instant::now() + Duration::from_secs(secs));
+    let parent_regex = match parent_regex {
+        Some(pattern) => Some(
+            Regex::new(&pattern).with_context(|| format!("Invalid parent regex '{}'", pattern))?,
+        ),
+        None => None,
+    };
+    if let Some(dir) = output_dir {
+        let (devices, timed_out) = list_devices(
+            env,
+            defined,
+            uuid,
+            parent.as_ref(),
+            parent_regex.as_ref(),
+            match_attr.as_deref(),
+            undefined_only,
+            deadline,
+        )?;
+        if timed_out {
+            warn!("Timed out looking up devices, only some devices were written");
+        }
+        dump_json_to_dir(&devices, &dir)?;
+        return match fail_empty && devices.is_empty() {
+            true => Err(anyhow!("No devices found")),
+            false => Ok(()),
+        };
+    }
+    if let Some(interval) = watch_interval {
+        return watch(out, interval, None, || {
+            // the timeout applies to each refresh of the list
+            let deadline = timeout.map(|secs| Instant::now() + Duration::from_secs(secs));
+            list_command_helper(
+                env,
+                defined,
+                dumpjson,
+                include_state,
+                verbose,
+                diff_attrs,
+                uuid,
+                parent.clone(),
+                parent_regex.as_ref(),
+                match_attr.as_deref(),
+                undefined_only,
+                deadline,
+            )
+            .map(|(output, _)| output)
+        });
+    }
+    let (output, count) = list_command_helper(
+        env,
+        defined,
+        dumpjson,
+        include_state,
+        verbose,
+        diff_attrs,
+        uuid,
+        parent,
+        parent_regex.as_ref(),
+        match_attr.as_deref(),
+        undefined_only,
+        deadline,
+    )?;
+    writeln!(out, "{}", output)?;
+    match fail_empty && count == 0 {
+        true => Err(anyhow!("No devices found")),
+        false => Ok(()),
+    }
+}
+
+/// Print the output of `render` to `out` every `interval`, clearing the screen before each frame,
+/// until `frames` frames have been printed, or forever if `frames` is None. A failure to render a
+/// frame is shown in place of the frame rather than ending the loop, since it may be due to devices
+/// changing while they are looked up.
+fn watch<F>(
+    out: &mut dyn Write,
+    interval: Duration,
+    frames: Option<u64>,
+    mut render: F,
+) -> Result<()>
+where
+    F: FnMut() -> Result<String>,
+{
+    let mut frame = 0;
+    while frames.is_none_or(|n| frame < n) {
+        let output = render().unwrap_or_else(|e| format!("Error: {:#}", e));
+        writeln!(out, "{}{}", CLEAR_SCREEN, output)?;
+        out.flush()?;
+        frame += 1;
+        if frames.is_none_or(|n| frame < n) {
+            std::thread::sleep(interval);
+        }
+    }
     Ok(())
 }
 
-/// convert 'list' command arguments into a text output
+/// convert 'list' command arguments into a text output, returned along with the number of devices
+/// listed
+#[allow(clippy::too_many_arguments)]
 fn list_command_helper(
     env: &dyn Environment,
     defined: bool,
     dumpjson: bool,
-    verbose: bool,
+    include_state: bool,
+    verbose: u8,
+    diff_attrs: bool,
     uuid: Option<Uuid>,
     parent: Option<String>,
-) -> Result<String> {
-    let mut devices: BTreeMap<String, Vec<MDev>> = BTreeMap::new();
-    if defined {
-        devices = defined_devices(env, uuid.as_ref(), parent.as_ref())?;
-    } else {
-        debug!("Looking up active mdevs");
-        if let Ok(dir) = env.mdev_base().read_dir() {
-            for dev in dir {
-                let dev = dev?;
-                let fname = dev.file_name();
-                let basename = fname.to_str().unwrap();
-                debug!("found defined mdev {}", basename);
-                let u = Uuid::parse_str(basename);
-
-                if u.is_err() {
-                    warn!("Can't determine uuid for file '{}'", basename);
-                    continue;
-                }
-                let u = u.unwrap();
-
-                if uuid.is_some() && uuid != Some(u) {
-                    debug!(
-                        "Ignoring device {} because it doesn't match uuid {}",
-                        u,
-                        uuid.unwrap()
-                    );
-                    continue;
-                }
-
-                let mut dev = MDev::new(env, u);
-                if dev.load_from_sysfs().is_ok() {
-                    if parent.is_some() && (parent != dev.parent) {
-                        debug!(
-                            "Ignoring device {} because it doesn't match parent {}",
-                            dev.uuid,
-                            parent.as_ref().unwrap()
-                        );
-                        continue;
-                    }
-
-                    let _ = dev.load_definition();
-
-                    let devparent = dev.parent()?;
-                    if !devices.contains_key(devparent) {
-                        devices.insert(devparent.clone(), Vec::new());
-                    };
-
-                    devices.get_mut(devparent).unwrap().push(dev);
-                };
-            }
-        }
-    }
-
-    // ensure that devices are sorted in a stable order
-    for v in devices.values_mut() {
-        v.sort_by_key(|e| e.uuid);
+    parent_regex: Option<&Regex>,
+    match_attr: Option<&str>,
+    undefined_only: bool,
+    deadline: Option<Instant>,
+) -> Result<(String, usize)> {
+    let (devices, timed_out) = list_devices(
+        env,
+        defined,
+        uuid,
+        parent.as_ref(),
+        parent_regex,
+        match_attr,
+        undefined_only,
+        deadline,
+    )?;
+    if timed_out {
+        warn!("Timed out looking up devices, the list is incomplete");
     }
 
+    let count = devices.values().flatten().count();
     let output = match dumpjson {
         true => {
             // if specified to a single device, output such that it can be piped into a config
             // file, else print entire heirarchy
-            if uuid.is_none() || devices.values().flatten().count() > 1 {
-                format_json(devices)?
+            if uuid.is_none() || count > 1 {
+                format_json(devices, include_state)?
             } else {
                 let jsonval = match devices.values().next() {
                     Some(children) => children
                         .first()
                         .ok_or_else(|| anyhow!("Failed to get device"))?
-                        .to_json(false)?,
+                        .to_json(false, false, include_state)?,
                     None => serde_json::json!([]),
                 };
                 serde_json::to_string_pretty(&jsonval)
@@ -518,108 +1316,371 @@ fn list_command_helper(
                 // convert child vector into an iterator over the vector's elements
                 .flat_map(|v| v.iter())
                 // convert MDev elements to a text representation, filtering out errors
-                .flat_map(|d| d.to_text(ft, verbose))
+                .flat_map(|d| d.to_text(ft, verbose, diff_attrs))
                 .collect::<String>()
         }
     };
-    Ok(output)
+    Ok((output, count))
 }
 
-/// Get a map of all mediated device types that are supported on this machine
-fn supported_types(
-    env: &dyn Environment,
-    parent: Option<String>,
-) -> Result<BTreeMap<String, Vec<MDevType>>> {
-    debug!("Finding supported mdev types");
-    let mut types: BTreeMap<String, Vec<MDevType>> = BTreeMap::new();
-
-    if let Ok(dir) = env.parent_base().read_dir() {
-        for parentpath in dir {
-            let parentpath = parentpath?;
-            let parentname = parentpath.file_name();
-            let parentname = parentname.to_str().unwrap();
-            debug!("Looking for supported types for device {}", parentname);
-            if parent.is_some() && parent.as_ref().unwrap() != parentname {
-                debug!("Ignoring types for parent {}", parentname);
+/// Look up the defined or active devices matching the given filters, grouped by parent and
+/// sorted by uuid. If `parent_regex` is given, only parents whose names match it are included.
+/// If `match_attr` is given, only devices with a matching attribute are included (see
+/// [`MDev::has_attr`]).
+///
+/// Loading device definitions stops once `deadline` has passed, in which case the devices found
+/// so far are returned and the second element of the result is true.
+#[allow(clippy::too_many_arguments)]
+fn list_devices<'a>(
+    env: &'a dyn Environment,
+    defined: bool,
+    uuid: Option<Uuid>,
+    parent: Option<&String>,
+    parent_regex: Option<&Regex>,
+    match_attr: Option<&str>,
+    undefined_only: bool,
+    deadline: Option<Instant>,
+) -> Result<(BTreeMap<String, Vec<MDev<'a>>>, bool)> {
+    let mut devices: BTreeMap<String, Vec<MDev>> = BTreeMap::new();
+    let mut timed_out = false;
+    if defined {
+        let mut defs =
+            UntilDeadline::new(iter_defined_devices(env, uuid.as_ref(), parent)?, deadline);
+        for dev in defs.by_ref() {
+            let dev = dev?;
+            devices.entry(dev.parent()?.clone()).or_default().push(dev);
+        }
+        timed_out = defs.timed_out;
+    } else {
+        let active = match undefined_only {
+            true => unmanaged_active_devices(env)?,
+            false => active_devices(env)?,
+        };
+        for dev in active {
+            if let Some(filter) = uuid.filter(|filter| *filter != dev.uuid) {
+                debug!(
+                    "Ignoring device {} because it doesn't match uuid {}",
+                    dev.uuid, filter
+                );
+                continue;
+            }
+            if let Some(p) = parent.filter(|p| Some(*p) != dev.parent.as_ref()) {
+                debug!(
+                    "Ignoring device {} because it doesn't match parent {}",
+                    dev.uuid, p
+                );
                 continue;
             }
+            devices.entry(dev.parent()?.clone()).or_default().push(dev);
+        }
+    }
 
-            let mut childtypes = Vec::new();
-            let mut parentpath = parentpath.path();
-            parentpath.push("mdev_supported_types");
-            for child in parentpath.read_dir()? {
-                let child = child?;
-                if !child.metadata()?.is_dir() {
-                    continue;
+    if let Some(pattern) = match_attr {
+        for children in devices.values_mut() {
+            children.retain(|dev| {
+                let matched = dev.has_attr(pattern);
+                if !matched {
+                    debug!(
+                        "Ignoring device {} because it doesn't match {}",
+                        dev.uuid, pattern
+                    );
                 }
+                matched
+            });
+        }
+        devices.retain(|_, children| !children.is_empty());
+    }
 
-                let mut t = MDevType::new();
-                t.parent = parentname.to_string();
+    if let Some(re) = parent_regex {
+        devices.retain(|p, _| {
+            let matched = re.is_match(p);
+            if !matched {
+                debug!("Ignoring parent {} because it doesn't match {}", p, re);
+            }
+            matched
+        });
+    }
 
-                let mut path = child.path();
-                t.typename = path.file_name().unwrap().to_str().unwrap().to_string();
-                debug!("found mdev type {}", t.typename);
+    // ensure that devices are sorted in a stable order
+    for v in devices.values_mut() {
+        v.sort_by_key(|e| e.uuid);
+    }
+    Ok((devices, timed_out))
+}
 
-                path.push("available_instances");
-                debug!("Checking available instances: {:?}", path);
-                t.available_instances = fs::read_to_string(&path)?.trim().parse()?;
+/// An iterator adapter which stops yielding items once a deadline has passed, recording whether
+/// it did so in `timed_out`
+struct UntilDeadline<I> {
+    inner: I,
+    deadline: Option<Instant>,
+    timed_out: bool,
+}
 
-                path.pop();
-                path.push("device_api");
-                t.device_api = fs::read_to_string(&path)?.trim().to_string();
+impl<I> UntilDeadline<I> {
+    fn new(inner: I, deadline: Option<Instant>) -> UntilDeadline<I> {
+        UntilDeadline {
+            inner,
+            deadline,
+            timed_out: false,
+        }
+    }
+}
 
-                path.pop();
-                path.push("name");
-                if path.exists() {
-                    t.name = fs::read_to_string(&path)?.trim().to_string();
-                }
+impl<I: Iterator> Iterator for UntilDeadline<I> {
+    type Item = I::Item;
 
-                path.pop();
-                path.push("description");
-                if path.exists() {
-                    t.description = fs::read_to_string(&path)?
-                        .trim()
-                        .replace("\n", ", ")
-                        .to_string();
-                }
+    fn next(&mut self) -> Option<I::Item> {
+        if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            debug!("Deadline reached, stopping enumeration");
+            self.timed_out = true;
+            return None;
+        }
+        self.inner.next()
+    }
+}
+
+/// Get all active devices, along with their definitions if they are defined
+fn active_devices(env: &dyn Environment) -> Result<Vec<MDev<'_>>> {
+    let mut devices = Vec::new();
+    debug!("Looking up active mdevs");
+    check_mdev_support(env)?;
+    if let Ok(dir) = env.mdev_base().read_dir() {
+        for dev in dir {
+            let dev = dev?;
+            let fname = dev.file_name();
+            let basename = fname.to_str().unwrap();
+            debug!("found active mdev {}", basename);
+            let u = Uuid::parse_str(basename);
+
+            if u.is_err() {
+                warn!("Can't determine uuid for file '{}'", basename);
+                continue;
+            }
+
+            let mut dev = MDev::new(env, u.unwrap());
+            if dev.load_from_sysfs().is_ok() {
+                let _ = dev.load_definition();
+                devices.push(dev);
+            }
+        }
+    }
+    Ok(devices)
+}
 
-                childtypes.push(t);
+/// Get all active devices which are not defined, such as devices created outside of mdevctl
+fn unmanaged_active_devices(env: &dyn Environment) -> Result<Vec<MDev<'_>>> {
+    Ok(active_devices(env)?
+        .into_iter()
+        .filter(|dev| {
+            if dev.is_defined() {
+                debug!("Ignoring device {} because it is defined", dev.uuid);
             }
-            types.insert(parentname.to_string(), childtypes);
+            !dev.is_defined()
+        })
+        .collect())
+}
+
+/// Write the json definition of each device to `dir`, mirroring the layout of the persistent
+/// configuration directory: one file per device, named by uuid, below a directory per parent.
+fn dump_json_to_dir(devices: &BTreeMap<String, Vec<MDev>>, dir: &Path) -> Result<()> {
+    for (parent, children) in devices {
+        for dev in children {
+            let path = dir.join(parent).join(dev.uuid.to_hyphenated().to_string());
+            // a copy outside of the configuration, which can also be written when read-only
+            dev.write_json(&path, &dev.to_json(false, false, false)?)
+                .with_context(|| format!("Failed to dump device {} to {:?}", dev.uuid, path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Get the mediated device types supported by the parent device `parentname`, reading only the
+/// types directory of that parent.
+fn parent_types(env: &dyn Environment, parentname: &str) -> Result<Vec<MDevType>> {
+    debug!("Looking for supported types for device {}", parentname);
+    let names = env.sysfs_names();
+    let mut childtypes = Vec::new();
+    let parentpath = env
+        .parent_base()
+        .join(parentname)
+        .join(names.supported_types);
+    for child in parentpath.read_dir()? {
+        let child = child?;
+        if !child.metadata()?.is_dir() {
+            continue;
+        }
+
+        let mut t = MDevType::new();
+        t.parent = parentname.to_string();
+
+        let mut path = child.path();
+        t.typename = path.file_name().unwrap().to_str().unwrap().to_string();
+        debug!("found mdev type {}", t.typename);
+
+        path.push(names.available_instances);
+        debug!("Checking available instances: {:?}", path);
+        t.available_instances = fs::read_to_string(&path)?.trim().parse()?;
+
+        path.pop();
+        path.push("device_api");
+        t.device_api = fs::read_to_string(&path)?.trim().to_string();
+
+        path.pop();
+        path.push("name");
+        if path.exists() {
+            t.name = fs::read_to_string(&path)?.trim().to_string();
         }
+
+        // some drivers describe a type over several lines, which are kept as they are
+        path.pop();
+        path.push("description");
+        if path.exists() {
+            t.description = fs::read_to_string(&path)?.trim_end().to_string();
+        }
+
+        childtypes.push(t);
     }
-    for v in types.values_mut() {
-        v.sort_by_key(|t| t.typename.clone());
+    childtypes.sort_by_key(|t| t.typename.clone());
+    Ok(childtypes)
+}
+
+/// Get a map of all mediated device types that are supported on this machine. If `parent` is
+/// given, only that parent device is looked at, and it is an error if it isn't present or doesn't
+/// support mediated devices.
+fn supported_types(
+    env: &dyn Environment,
+    parent: Option<String>,
+) -> Result<BTreeMap<String, Vec<MDevType>>> {
+    debug!("Finding supported mdev types");
+    check_mdev_support(env)?;
+    let mut types: BTreeMap<String, Vec<MDevType>> = BTreeMap::new();
+
+    if let Some(parent) = parent {
+        let parentname = resolve_parent(env, &parent)?;
+        if !env
+            .parent_base()
+            .join(&parentname)
+            .join(env.sysfs_names().supported_types)
+            .is_dir()
+        {
+            return Err(anyhow!(
+                "Parent {} does not support mediated devices",
+                parent
+            ));
+        }
+        let children = parent_types(env, &parentname)?;
+        types.insert(parentname, children);
+        return Ok(types);
+    }
+
+    if let Ok(dir) = env.parent_base().read_dir() {
+        for parentpath in dir {
+            let parentpath = parentpath?;
+            let parentname = parentpath.file_name();
+            let parentname = parentname.to_str().unwrap();
+            types.insert(parentname.to_string(), parent_types(env, parentname)?);
+        }
     }
     Ok(types)
 }
 
+/// Get the supported types of each parent device in `types` in json format along with a summary
+/// of the capabilities of the parent: whether it supports mediated devices, the device APIs of its
+/// types, its number of active devices, and the most instances of any one of its types that are
+/// still available. Instances of different types may share the capacity of the parent, so their
+/// availability is not added up. The summary is computed from `all_types`, the types of each
+/// parent before any filtering, so that it doesn't depend on which types are listed.
+fn parent_summary_json(
+    env: &dyn Environment,
+    all_types: &BTreeMap<String, Vec<MDevType>>,
+    types: &BTreeMap<String, Vec<MDevType>>,
+) -> Result<serde_json::Value> {
+    let active = active_devices(env)?;
+    let mut parents = serde_json::map::Map::new();
+    for (parentname, children) in types {
+        let supported = env
+            .parent_base()
+            .join(parentname)
+            .join(env.sysfs_names().supported_types)
+            .is_dir();
+        let all_children = all_types.get(parentname).unwrap_or(children);
+        let apis: BTreeSet<&str> = all_children.iter().map(|t| t.device_api.as_str()).collect();
+        let max_available = all_children
+            .iter()
+            .map(|t| t.available_instances)
+            .max()
+            .unwrap_or(0);
+        let active = active
+            .iter()
+            .filter(|d| d.parent.as_deref() == Some(parentname.as_str()))
+            .count();
+        let mut typearray = Vec::new();
+        for child in children {
+            typearray.push(child.to_json()?);
+        }
+        parents.insert(
+            parentname.clone(),
+            serde_json::json!({
+                "summary": {
+                    "mdev_supported": supported,
+                    "device_apis": apis,
+                    "active_instances": active,
+                    "max_available_instances": max_available,
+                },
+                "types": typearray,
+            }),
+        );
+    }
+    // don't serialize an empty object if there are no parents
+    Ok(match parents.len() {
+        0 => serde_json::json!([]),
+        _ => serde_json::json!([parents]),
+    })
+}
+
 /// convert 'types' command arguments into a text output
 fn types_command_helper(
     env: &dyn Environment,
     parent: Option<String>,
     dumpjson: bool,
+    parent_summary: bool,
+    available_only: bool,
+    missing_from: Option<PathBuf>,
+    describe: DescriptionFormatter,
 ) -> Result<String> {
-    let types = supported_types(env, parent)?;
+    let mut types = supported_types(env, parent)?;
+    // the summary of a parent describes all of its types, not only those listed
+    let summary_types = (dumpjson && parent_summary).then(|| types.clone());
+    if let Some(path) = missing_from {
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read types file {:?}", path))?;
+        let other = read_types_json(&serde_json::from_str(&contents)?)
+            .with_context(|| format!("Unable to load types from {:?}", path))?;
+        let ours: Vec<MDevType> = types.into_values().flatten().collect();
+        types = BTreeMap::new();
+        for t in type_set_difference(&ours, &other) {
+            types.entry(t.parent.clone()).or_default().push(t);
+        }
+    }
+    if available_only {
+        for children in types.values_mut() {
+            children.retain(|t| t.available_instances > 0);
+        }
+        types.retain(|_, children| !children.is_empty());
+    }
     let mut output = String::new();
     debug!("{:?}", types);
-    if dumpjson {
-        let mut parents = serde_json::map::Map::new();
-        for (parent, children) in types {
-            let mut childarray = Vec::new();
-            for child in children {
-                childarray.push(child.to_json()?);
-            }
-            parents.insert(parent, childarray.into());
-        }
-
-        let jsonval = match parents.len() {
-            0 => serde_json::json!([]),
-            _ => serde_json::json!([parents]),
-        };
-        let jsonstr = serde_json::to_string_pretty(&jsonval)
-            .map_err(|_e| anyhow!("Unable to serialize json"))?;
-        output.push_str(&jsonstr);
+    if let Some(all_types) = summary_types {
+        let jsonval = parent_summary_json(env, &all_types, &types)?;
+        output.push_str(
+            &serde_json::to_string_pretty(&jsonval)
+                .map_err(|_e| anyhow!("Unable to serialize json"))?,
+        );
+    } else if dumpjson {
+        let types: Vec<MDevType> = types.into_values().flatten().collect();
+        let mut jsonbytes = Vec::new();
+        write_types_json(&types, &mut jsonbytes, true)?;
+        output.push_str(&String::from_utf8(jsonbytes)?);
     } else {
         for (parent, children) in types {
             output.push_str(&format!("{}\n", parent));
@@ -634,7 +1695,12 @@ fn types_command_helper(
                     output.push_str(&format!("    Name: {}\n", child.name));
                 }
                 if !child.description.is_empty() {
-                    output.push_str(&format!("    Description: {}\n", child.description));
+                    // continuation lines are aligned with the first line of the description
+                    let description = describe(&child.description)
+                        .lines()
+                        .collect::<Vec<_>>()
+                        .join("\n                 ");
+                    output.push_str(&format!("    Description: {}\n", description));
                 }
             }
         }
@@ -643,9 +1709,47 @@ fn types_command_helper(
 }
 
 /// Implementation of the `mdevctl types` command
-fn types_command(env: &dyn Environment, parent: Option<String>, dumpjson: bool) -> Result<()> {
-    let output = types_command_helper(env, parent, dumpjson)?;
-    println!("{}", output);
+#[allow(clippy::too_many_arguments)]
+fn types_command(
+    env: &dyn Environment,
+    parent: Option<String>,
+    dumpjson: bool,
+    parent_summary: bool,
+    available_only: bool,
+    missing_from: Option<PathBuf>,
+    human: bool,
+    null_output: bool,
+) -> Result<()> {
+    let describe = match human {
+        true => humanize_byte_sizes,
+        false => humanize_description,
+    };
+    let output = types_command_helper(
+        env,
+        parent,
+        dumpjson,
+        parent_summary,
+        available_only,
+        missing_from,
+        describe,
+    )?;
+    if !null_output {
+        println!("{}", output);
+    }
+    Ok(())
+}
+
+/// Implementation of the `mdevctl types --count` command
+fn count_command(
+    env: &dyn Environment,
+    parent: &str,
+    mdev_type: &str,
+    null_output: bool,
+) -> Result<()> {
+    let count = count_available(env, parent, mdev_type)?;
+    if !null_output {
+        println!("{}", count);
+    }
     Ok(())
 }
 
@@ -663,7 +1767,7 @@ fn start_parent_mdevs_command(env: &dyn Environment, parent: String) -> Result<(
         for child in children {
             if child.autostart {
                 debug!("Autostarting {:?}", child.uuid);
-                if let Err(e) = child.start() {
+                if let Err(e) = child.start(false, None) {
                     for x in e.chain() {
                         warn!("{}", x);
                     }
@@ -676,68 +1780,203 @@ fn start_parent_mdevs_command(env: &dyn Environment, parent: String) -> Result<(
 
 /// parse command line arguments and dispatch to command-specific functions
 fn main() -> Result<()> {
-    logger().init();
+    // check if we're running as the symlink executable 'lsmdev'. If so, just execute the 'list'
+    // command directly
+    let exe = std::env::args_os().next().unwrap();
+    let lsmdev = exe.to_str().is_some_and(|val| val.ends_with("lsmdev"));
+    let command = match lsmdev {
+        true => MdevctlCommands::List(LsmdevOptions::from_args()),
+        false => MdevctlCommands::from_args(),
+    };
+
+    // with --output=null, the result is only reported by the exit code
+    let null_output = match &command {
+        MdevctlCommands::List(list) => list.output.is_some(),
+        MdevctlCommands::Export { output, .. }
+        | MdevctlCommands::Types { output, .. }
+        | MdevctlCommands::Verify { output, .. } => output.is_some(),
+        _ => false,
+    };
+    let mut builder = logger();
+    if let MdevctlCommands::List(list) = &command {
+        if let Some(level) = verbosity_level(list.verbose) {
+            builder.filter_level(level);
+        }
+    }
+    if let Some(level) = silent_level().filter(|_| null_output) {
+        builder.filter_level(level);
+    }
+    builder.init();
     debug!("Starting up");
+    if lsmdev {
+        debug!("running as 'lsmdev'");
+    }
 
     let env = DefaultEnvironment::new();
     debug!("{:?}", env);
-    // check if we're running as the symlink executable 'lsmdev'. If so, just execute the 'list'
-    // command directly
-    let exe = std::env::args_os().next().unwrap();
-    match exe.to_str() {
-        Some(val) if val.ends_with("lsmdev") => {
-            debug!("running as 'lsmdev'");
-            let opts = LsmdevOptions::from_args();
-            list_command(
+    let result = match command {
+        MdevctlCommands::Define {
+            uuid,
+            auto,
+            parent,
+            mdev_type,
+            jsonfile,
+            start,
+            keep_on_failure,
+            dedup,
+            print_json_on_success,
+        } => define_command(
+            &env,
+            uuid,
+            auto,
+            parent,
+            mdev_type,
+            jsonfile,
+            start,
+            keep_on_failure,
+            dedup,
+            print_json_on_success,
+        ),
+        MdevctlCommands::Adopt { uuid, auto, force } => adopt_command(&env, uuid, auto, force),
+        MdevctlCommands::Undefine {
+            uuid,
+            parent,
+            mdev_type,
+            uuid_prefix,
+            dry_run,
+            yes,
+        } => match uuid {
+            Some(uuid) => undefine_command(&env, uuid, parent),
+            None => undefine_matching_command(&env, parent, mdev_type, uuid_prefix, dry_run, yes),
+        },
+        MdevctlCommands::Export {
+            uuid,
+            parent,
+            flat_attrs,
+            sort_attrs,
+            ..
+        } => export_command(&env, uuid, parent, flat_attrs, sort_attrs, null_output),
+        MdevctlCommands::Modify {
+            uuid,
+            parent,
+            mdev_type,
+            addattr,
+            delattr,
+            delattr_name,
+            all,
+            index,
+            value,
+            auto,
+            manual,
+            apply_now,
+            dry_run,
+            new_uuid,
+            dedup,
+            print_json_on_success,
+        } => modify_command(
+            &env,
+            uuid,
+            parent,
+            mdev_type,
+            addattr,
+            delattr,
+            delattr_name,
+            all,
+            index,
+            value,
+            auto,
+            manual,
+            apply_now,
+            dry_run,
+            new_uuid,
+            dedup,
+            print_json_on_success,
+        ),
+        MdevctlCommands::Start {
+            uuid,
+            parent,
+            mdev_type,
+            jsonfile,
+            strict,
+            timeout,
+            retries,
+            retry_delay,
+            print_json_on_success,
+        } => start_command(
+            &env,
+            uuid,
+            parent,
+            mdev_type,
+            jsonfile,
+            strict,
+            timeout,
+            retries,
+            retry_delay,
+            print_json_on_success,
+        ),
+        MdevctlCommands::Stop { uuid, force, wait } => stop_command(&env, uuid, force, wait),
+        MdevctlCommands::List(list) => list_command(
+            &env,
+            list.defined,
+            list.dumpjson,
+            list.include_state,
+            list.verbose,
+            list.diff_attrs,
+            list.uuid,
+            list.parent,
+            list.parent_regex,
+            list.match_attr,
+            list.undefined_only,
+            list.output_dir,
+            list.timeout,
+            list.fail_empty,
+            list.watch.then_some(list.interval),
+            null_output,
+            &mut std::io::stdout(),
+        ),
+        MdevctlCommands::Types {
+            parent,
+            dumpjson,
+            parent_summary,
+            available_only,
+            missing_from,
+            human,
+            count,
+            ..
+        } => match count {
+            Some(mdev_type) => {
+                count_command(&env, parent.as_deref().unwrap(), &mdev_type, null_output)
+            }
+            None => types_command(
                 &env,
-                opts.defined,
-                opts.dumpjson,
-                opts.verbose,
-                opts.uuid,
-                opts.parent,
-            )
-        }
-        _ => match MdevctlCommands::from_args() {
-            MdevctlCommands::Define {
-                uuid,
-                auto,
-                parent,
-                mdev_type,
-                jsonfile,
-            } => define_command(&env, uuid, auto, parent, mdev_type, jsonfile),
-            MdevctlCommands::Undefine { uuid, parent } => undefine_command(&env, uuid, parent),
-            MdevctlCommands::Modify {
-                uuid,
-                parent,
-                mdev_type,
-                addattr,
-                delattr,
-                index,
-                value,
-                auto,
-                manual,
-            } => modify_command(
-                &env, uuid, parent, mdev_type, addattr, delattr, index, value, auto, manual,
-            ),
-            MdevctlCommands::Start {
-                uuid,
                 parent,
-                mdev_type,
-                jsonfile,
-            } => start_command(&env, uuid, parent, mdev_type, jsonfile),
-            MdevctlCommands::Stop { uuid } => stop_command(&env, uuid),
-            MdevctlCommands::List(list) => list_command(
-                &env,
-                list.defined,
-                list.dumpjson,
-                list.verbose,
-                list.uuid,
-                list.parent,
+                dumpjson,
+                parent_summary,
+                available_only,
+                missing_from,
+                human,
+                null_output,
             ),
-            MdevctlCommands::Types { parent, dumpjson } => types_command(&env, parent, dumpjson),
-            MdevctlCommands::StartParentMdevs { parent } => {
-                start_parent_mdevs_command(&env, parent)
-            }
         },
+        MdevctlCommands::Migrate { dry_run } => migrate_command(&env, dry_run),
+        MdevctlCommands::Prune { dry_run, yes } => prune_command(&env, dry_run, yes),
+        MdevctlCommands::Verify { uuid, parent, .. } => {
+            verify_command(&env, uuid, parent, null_output)
+        }
+        MdevctlCommands::Wait {
+            uuid,
+            timeout,
+            attr,
+        } => wait_command(&env, uuid, timeout, attr),
+        MdevctlCommands::StartParentMdevs { parent } => start_parent_mdevs_command(&env, parent),
+    };
+
+    // report failures as a json object on stderr if requested via the environment
+    if env_flag("MDEVCTL_JSON_ERRORS") {
+        if let Err(e) = &result {
+            eprintln!("{}", error_to_json(e));
+            std::process::exit(1);
+        }
     }
+    result
 }
@@ -1,12 +1,18 @@
 //! Structures for representing a mediated device
 
-use crate::environment::Environment;
+use crate::environment::{AttrNewline, Environment, PersistLayout};
 use anyhow::{anyhow, Context, Result};
 use log::{debug, warn};
-use std::convert::TryInto;
+use regex::Regex;
+use std::collections::{BTreeMap, HashSet};
+use std::convert::{TryFrom, TryInto};
 use std::fs;
-use std::io::Read;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 use uuid::Uuid;
 
@@ -49,12 +55,9 @@ impl<'a> MDev<'a> {
 
     // get parent and propagate a consistent error to the caller if absent
     pub fn parent(&self) -> Result<&String> {
-        self.parent.as_ref().ok_or_else(|| {
-            anyhow!(
-                "Device {} is missing a parent",
-                self.uuid.to_hyphenated().to_string()
-            )
-        })
+        self.parent
+            .as_ref()
+            .ok_or_else(|| anyhow!("Device {} is missing a parent", self.uuid.to_hyphenated()))
     }
 
     // get mdev_type and propagate a consistent error to the caller if absent
@@ -62,23 +65,49 @@ impl<'a> MDev<'a> {
         self.mdev_type.as_ref().ok_or_else(|| {
             anyhow!(
                 "Device {} is missing a mdev_type",
-                self.uuid.to_hyphenated().to_string()
+                self.uuid.to_hyphenated()
             )
         })
     }
 
+    /// Get the device API of the device's type, e.g. `vfio-pci` or `vfio-ccw`, as reported by the
+    /// parent device
+    pub fn device_api(&self) -> Result<String> {
+        let path = self
+            .env
+            .parent_base()
+            .join(self.parent()?)
+            .join(self.env.sysfs_names().supported_types)
+            .join(self.mdev_type()?)
+            .join("device_api");
+        let api = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read device api from {:?}", path))?;
+        Ok(api.trim().to_string())
+    }
+
+    /// Get the path of the file holding the definition of this device. Depending on the
+    /// environment's [`PersistLayout`], this file may also hold the definitions of other devices.
     pub fn persist_path(&self) -> Option<PathBuf> {
         self.parent.as_ref().map(|x| {
             let mut path = self.env.persist_base();
-            path.push(x);
-            path.push(self.uuid.to_hyphenated().to_string());
+            match self.env.persist_layout() {
+                PersistLayout::PerDevice => {
+                    path.push(x);
+                    path.push(self.uuid.to_hyphenated().to_string());
+                }
+                PersistLayout::PerParent => path.push(format!("{}.json", x)),
+            }
             path
         })
     }
 
     pub fn is_defined(&self) -> bool {
         match self.persist_path() {
-            Some(p) => p.exists(),
+            Some(p) => match self.env.persist_layout() {
+                PersistLayout::PerDevice => p.exists(),
+                PersistLayout::PerParent => read_aggregate(&p)
+                    .is_ok_and(|defs| defs.contains_key(&self.uuid.to_hyphenated().to_string())),
+            },
             None => false,
         }
     }
@@ -93,86 +122,92 @@ impl<'a> MDev<'a> {
         let canonpath = self.path().canonicalize()?;
         let sysfsparent = canonpath.parent().unwrap();
         let parentname = canonical_basename(sysfsparent)?;
-        if self.parent.is_some() && self.parent.as_ref() != Some(&parentname) {
+        if let Some(p) = self
+            .parent
+            .as_ref()
+            .filter(|p| !self.is_parent_name(p, &parentname))
+        {
             debug!(
                 "Active mdev {:?} has different parent: {}!={}. No match.",
-                self.uuid,
-                self.parent.as_ref().unwrap(),
-                parentname
+                self.uuid, p, parentname
             );
             return Ok(());
         }
         let mut typepath = self.path();
         typepath.push("mdev_type");
-        let mdev_type = canonical_basename(typepath)?;
-        if self.mdev_type.is_some() && self.mdev_type.as_ref() != Some(&mdev_type) {
+        let mdev_type = canonical_basename(&typepath)?;
+        if let Some(t) = self
+            .mdev_type
+            .as_ref()
+            .filter(|t| !self.is_type_name(t, &typepath, &parentname, &mdev_type))
+        {
             debug!(
                 "Active mdev {:?} has different type: {}!={}. No match.",
-                self.uuid,
-                self.mdev_type.as_ref().unwrap(),
-                mdev_type
+                self.uuid, t, mdev_type
             );
             return Ok(());
         }
 
-        // active device in sysfs matches this device. update information
-        self.mdev_type = Some(mdev_type);
-        self.parent = Some(parentname);
+        // active device in sysfs matches this device. update information, keeping the parent and
+        // type names that were given in case they are aliases of the actual ones
+        if self.mdev_type.is_none() {
+            self.mdev_type = Some(mdev_type);
+        }
+        if self.parent.is_none() {
+            self.parent = Some(parentname);
+        }
         self.active = true;
+
+        // some kernels expose whether the device is intended to be started automatically. The
+        // stored definition takes precedence, so only consult it for devices that aren't defined
+        if !self.is_defined() {
+            if let Some(autostart) = self.sysfs_autostart() {
+                self.autostart = autostart;
+            }
+        }
         debug!("loaded device {:?}", self);
         Ok(())
     }
 
+    // best-effort read of the autostart hint exposed by the device in sysfs, if any
+    fn sysfs_autostart(&self) -> Option<bool> {
+        let path = self.path().join("autostart");
+        let val = fs::read_to_string(&path).ok()?;
+        match val.trim() {
+            "1" | "y" | "Y" | "auto" => Some(true),
+            "0" | "n" | "N" | "manual" => Some(false),
+            other => {
+                debug!(
+                    "Ignoring unrecognized autostart value '{}' in {:?}",
+                    other, path
+                );
+                None
+            }
+        }
+    }
+
     pub fn load_from_json(&mut self, parent: String, json: &serde_json::Value) -> Result<()> {
         debug!(
             "Loading device '{:?}' from json (parent: {})",
             self.uuid, parent
         );
-        if self.parent.is_some() && self.parent.as_ref() != Some(&parent) {
+        if let Some(old) = self.parent.as_ref().filter(|p| **p != parent) {
             warn!(
                 "Overwriting parent for mdev {:?}: {} => {}",
-                self.uuid,
-                self.parent.as_ref().unwrap(),
-                parent
+                self.uuid, old, parent
             );
         }
         self.parent = Some(parent);
-        if json["mdev_type"].is_null() || json["start"].is_null() {
-            return Err(anyhow!("invalid json"));
-        }
-        let mdev_type = json["mdev_type"].as_str().unwrap().to_string();
-        if self.mdev_type.is_some() && self.mdev_type.as_ref() != Some(&mdev_type) {
+        let config = DeviceConfig::from_json(json)?;
+        if let Some(old) = self.mdev_type.as_ref().filter(|p| **p != config.mdev_type) {
             warn!(
                 "Overwriting mdev type for mdev {:?}: {} => {}",
-                self.uuid,
-                self.mdev_type.as_ref().unwrap(),
-                mdev_type
+                self.uuid, old, config.mdev_type
             );
         }
-        self.mdev_type = Some(mdev_type);
-        let startval = json["start"].as_str();
-        self.autostart = matches!(startval, Some("auto"));
-
-        if let Some(attrarray) = json["attrs"].as_array() {
-            if !attrarray.is_empty() {
-                for attr in attrarray {
-                    let attrobj = attr.as_object().ok_or_else(|| {
-                        anyhow!("invalid JSON format for attribute: not an object")
-                    })?;
-                    // attributes are represented by JSON objects with a single field.
-                    if attrobj.len() != 1 {
-                        return Err(anyhow!(
-                            "invalid JSON format for attribute: too many fields"
-                        ));
-                    }
-                    // get the key and value from the first (only) map entry
-                    if let Some((key, val)) = attrobj.iter().next() {
-                        let valstr = val.as_str().unwrap();
-                        self.attrs.push((key.to_string(), valstr.to_string()));
-                    }
-                }
-            }
-        };
+        self.mdev_type = Some(config.mdev_type);
+        self.autostart = config.autostart;
+        self.attrs.extend(config.attrs);
         debug!("loaded device {:?}", self);
 
         Ok(())
@@ -181,17 +216,153 @@ impl<'a> MDev<'a> {
     // load the stored definition from disk if it exists
     pub fn load_definition(&mut self) -> Result<()> {
         if let Some(path) = self.persist_path() {
-            let mut f = fs::File::open(path)?;
-            let mut contents = String::new();
-            f.read_to_string(&mut contents)?;
-            let val = serde_json::from_str(&contents)?;
+            let val = match self.env.persist_layout() {
+                PersistLayout::PerDevice => {
+                    let mut contents = String::new();
+                    fs::File::open(&path)
+                        .and_then(|mut f| f.read_to_string(&mut contents))
+                        .with_context(|| format!("reading definition {:?}", path))?;
+                    let val = serde_json::from_str(&contents)
+                        .with_context(|| format!("parsing definition {:?}", path))?;
+                    let (val, wrapped) = unwrap_legacy_json(&self.uuid, val);
+                    if wrapped {
+                        warn!(
+                            "Definition {:?} is in a legacy format, use 'mdevctl migrate' to \
+                             convert it",
+                            path
+                        );
+                    }
+                    val
+                }
+                PersistLayout::PerParent => read_aggregate(&path)?
+                    .remove(&self.uuid.to_hyphenated().to_string())
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "reading definition {:?}: no entry for {}",
+                            path,
+                            self.uuid.to_hyphenated()
+                        )
+                    })?,
+            };
             let parent = self.parent.as_ref().unwrap().clone();
             self.load_from_json(parent, &val)?;
         }
         Ok(())
     }
 
-    pub fn to_text(&self, fmt: FormatType, verbose: bool) -> Result<String> {
+    // whether `name` refers to the parent device `actual`, either directly or as a symlink to it in
+    // the parent device directory
+    fn is_parent_name(&self, name: &str, actual: &str) -> bool {
+        name == actual
+            || canonical_basename(self.env.parent_base().join(name)).is_ok_and(|c| c == actual)
+    }
+
+    // whether `name` refers to the type `actual` of the active device, whose `mdev_type` link is
+    // `typepath`: either directly, as the name the link points to, or as an alias of the type
+    // among the supported types of `parent`
+    fn is_type_name(&self, name: &str, typepath: &Path, parent: &str, actual: &str) -> bool {
+        let linked = fs::read_link(typepath)
+            .ok()
+            .and_then(|p| p.file_name().map(|f| f.to_string_lossy().to_string()));
+        let alias = self
+            .env
+            .parent_base()
+            .join(parent)
+            .join(self.env.sysfs_names().supported_types)
+            .join(name);
+        name == actual
+            || linked.is_some_and(|l| l == name)
+            || canonical_basename(alias).is_ok_and(|c| c == actual)
+    }
+
+    /// Read the current values of the attributes of an active device from sysfs. Only regular
+    /// files which are both readable and writable are considered attributes, excluding standard
+    /// device control files. Attributes are returned sorted by name.
+    pub fn sysfs_attrs(&self) -> Result<Vec<(String, String)>> {
+        let ignored = ["uevent", self.env.sysfs_names().remove, "driver_override"];
+
+        let mut attrs = Vec::new();
+        for entry in self.path().read_dir()? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let metadata = match fs::symlink_metadata(entry.path()) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            if !metadata.is_file() || ignored.contains(&name.as_str()) {
+                continue;
+            }
+            if metadata.permissions().mode() & 0o600 != 0o600 {
+                debug!("Ignoring non-writable attribute {}", name);
+                continue;
+            }
+            match fs::read_to_string(entry.path()) {
+                Ok(val) => attrs.push((name, val.trim_end().to_string())),
+                Err(e) => debug!("Unable to read attribute {}: {}", name, e),
+            }
+        }
+        attrs.sort();
+        Ok(attrs)
+    }
+
+    /// The live attribute values of an active device, kept separately from the defined attributes
+    /// in `attrs`. They are read from sysfs on each call, or None if the device isn't active or its
+    /// attributes can't be read.
+    pub fn active_attrs(&self) -> Option<Vec<(String, String)>> {
+        if !self.active {
+            return None;
+        }
+        match self.sysfs_attrs() {
+            Ok(attrs) => Some(attrs),
+            Err(e) => {
+                debug!("Unable to read attributes of {:?}: {}", self.uuid, e);
+                None
+            }
+        }
+    }
+
+    /// Compare the live attributes of this device against `desired`, returning a description of
+    /// each attribute whose current value differs. If an attribute is listed more than once, the
+    /// last value is the one expected since it is written last.
+    fn attr_differences(&self, desired: &[(String, String)]) -> Vec<String> {
+        let desired: BTreeMap<&String, &String> = desired.iter().map(|(k, v)| (k, v)).collect();
+        let active = self.active_attrs();
+        let live: BTreeMap<&String, &String> =
+            active.iter().flatten().map(|(k, v)| (k, v)).collect();
+        desired
+            .into_iter()
+            .filter_map(|(name, val)| match live.get(name) {
+                Some(current) if *current == val => None,
+                Some(current) => Some(format!(
+                    "{} (expected '{}', found '{}')",
+                    name, val, current
+                )),
+                None => Some(format!("{} (expected '{}', not present)", name, val)),
+            })
+            .collect()
+    }
+
+    // best-effort read of the description some drivers provide for an attribute in a companion
+    // '<attr>_help' file of an active device. Only the first line is used.
+    fn attr_help(&self, attr: &str) -> Option<String> {
+        if !self.active {
+            return None;
+        }
+        let path = self.path().join(format!("{}_help", attr));
+        let contents = fs::read_to_string(path).ok()?;
+        let help = contents.lines().next()?.trim();
+        match help.is_empty() {
+            true => None,
+            false => Some(help.to_string()),
+        }
+    }
+
+    /// Format the device as a line of text, followed by more details depending on the `verbose`
+    /// level: its attributes from level 1, and the device API from level 2. With `diff_attrs` or
+    /// from level 3, the attributes of a device which is both defined and active are compared
+    /// against their live values, and each one that differs is marked with a `*` and followed by
+    /// its live value.
+    pub fn to_text(&self, fmt: FormatType, verbose: u8, diff_attrs: bool) -> Result<String> {
         match fmt {
             FormatType::Defined => {
                 if !self.is_defined() {
@@ -207,9 +378,9 @@ impl<'a> MDev<'a> {
 
         let mut output = self.uuid.to_hyphenated().to_string();
         output.push(' ');
-        output.push_str(&self.parent()?);
+        output.push_str(self.parent()?);
         output.push(' ');
-        output.push_str(&self.mdev_type()?);
+        output.push_str(self.mdev_type()?);
         output.push(' ');
         output.push_str(match self.autostart {
             true => "auto",
@@ -230,30 +401,95 @@ impl<'a> MDev<'a> {
         }
 
         output.push('\n');
-        if verbose && !self.attrs.is_empty() {
+        if verbose >= 2 {
+            if let Some(api) = self.device_api().ok().filter(|api| !api.is_empty()) {
+                output.push_str(&format!("  Device API: {}\n", api));
+            }
+        }
+        if verbose >= 1 && !self.attrs.is_empty() {
+            let diff_attrs = diff_attrs || verbose >= 3;
+            let live = match diff_attrs && self.active && self.is_defined() {
+                true => self.active_attrs(),
+                false => None,
+            };
+            // compare the values that would be written, rather than any references in them
+            let resolved = self.resolved_attrs().unwrap_or_else(|_| self.attrs.clone());
             output.push_str("  Attrs:\n");
             for (i, (key, value)) in self.attrs.iter().enumerate() {
-                let txtattr = format!("    @{{{}}}: {{\"{}\":\"{}\"}}\n", i, key, value);
+                // only the last value written to an attribute is expected to be live
+                let last = !self.attrs[i + 1..].iter().any(|(k, _)| k == key);
+                let difference = live.as_ref().filter(|_| last).and_then(|live| {
+                    match live.iter().find(|(k, _)| k == key) {
+                        Some((_, v)) if *v == resolved[i].1 => None,
+                        Some((_, v)) => Some(format!("(active: \"{}\")", v)),
+                        None => Some("(active: not present)".to_string()),
+                    }
+                });
+                let marker = match difference {
+                    Some(_) => "  * ",
+                    None => "    ",
+                };
+                let txtattr = format!("{}@{{{}}}: {{\"{}\":\"{}\"}}", marker, i, key, value);
                 output.push_str(&txtattr);
+                if let Some(d) = difference {
+                    output.push_str("  ");
+                    output.push_str(&d);
+                }
+                if let Some(help) = self.attr_help(key) {
+                    output.push_str("  # ");
+                    output.push_str(&help);
+                }
+                output.push('\n');
             }
         }
         Ok(output)
     }
 
-    pub fn to_json(&self, include_uuid: bool) -> Result<serde_json::Value> {
-        let autostart = match self.autostart {
-            true => "auto",
-            false => "manual",
-        };
-        let mut partial = serde_json::Map::new();
-        partial.insert("mdev_type".to_string(), self.mdev_type()?.clone().into());
-        partial.insert("start".to_string(), autostart.into());
-        let jsonattrs: Vec<_> = self
-            .attrs
-            .iter()
-            .map(|(key, value)| serde_json::json!({ key: value }))
-            .collect();
-        partial.insert("attrs".to_string(), jsonattrs.into());
+    /// Get the definition of the device in json format like `to_json()`, but with the attributes
+    /// sorted by name so that the output is stable for diffs and backups. Attributes are written
+    /// in the order they are listed, so this form must not be used to recreate a device whose
+    /// attributes depend on that order.
+    pub fn to_json_sorted(
+        &self,
+        include_uuid: bool,
+        flat_attrs: bool,
+    ) -> Result<serde_json::Value> {
+        let mut sorted = self.clone();
+        sorted.attrs.sort_by(|a, b| a.0.cmp(&b.0));
+        sorted.to_json(include_uuid, flat_attrs, false)
+    }
+
+    /// Get the definition of the device in json format, keyed by its uuid if `include_uuid` is set.
+    /// Attributes are an array of single-key objects in the order they are written, or a single
+    /// object if `flat_attrs` is set, which fails if an attribute is listed more than once. The
+    /// runtime state of the device is only included, as "active" and "defined", if
+    /// `include_state` is set.
+    pub fn to_json(
+        &self,
+        include_uuid: bool,
+        flat_attrs: bool,
+        include_state: bool,
+    ) -> Result<serde_json::Value> {
+        let mut partial = DeviceConfig::try_from(self)?.to_json();
+        if flat_attrs {
+            let mut flat = serde_json::Map::new();
+            for (key, value) in self.attrs.iter() {
+                if flat.insert(key.clone(), value.clone().into()).is_some() {
+                    return Err(anyhow!(
+                        "Attribute '{}' of device {} is listed more than once and can't be \
+                         represented as a flat object",
+                        key,
+                        self.uuid.to_hyphenated()
+                    ));
+                }
+            }
+            // replacing the value keeps the position of the field
+            partial.insert("attrs".to_string(), flat.into());
+        }
+        if include_state {
+            partial.insert("active".to_string(), self.active.into());
+            partial.insert("defined".to_string(), self.is_defined().into());
+        }
 
         let full = serde_json::json!({ self.uuid.to_hyphenated().to_string(): partial });
 
@@ -263,41 +499,111 @@ impl<'a> MDev<'a> {
         }
     }
 
-    pub fn stop(&mut self) -> Result<()> {
+    // refuse to perform a mutating operation if the environment is read-only
+    fn ensure_writable(&self) -> Result<()> {
+        if self.env.read_only() {
+            return Err(anyhow!(
+                "Refusing to modify device {}: environment is read-only",
+                self.uuid.to_hyphenated()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Remove the device. If `force` is set and the device is busy, the removal is retried a few
+    /// times over a short window to give transient users of the device a chance to release it.
+    /// Some drivers tear the device down asynchronously, so if `wait` is given, this only succeeds
+    /// once the device is gone, waiting for it up to that long.
+    pub fn stop(&mut self, force: bool, wait: Option<Duration>) -> Result<()> {
+        self.ensure_writable()?;
         debug!("Removing mdev {:?}", self.uuid);
         let mut remove_path = self.path();
-        remove_path.push("remove");
+        remove_path.push(self.env.sysfs_names().remove);
         debug!("remove path '{:?}'", remove_path);
-        match fs::write(remove_path, "1") {
+        let attempts = match force {
+            true => FORCE_REMOVE_ATTEMPTS,
+            false => 1,
+        };
+        match retry_busy(attempts, FORCE_REMOVE_DELAY, || {
+            fs::write(&remove_path, "1")
+        }) {
             Ok(_) => {
+                if let Some(timeout) = wait {
+                    self.wait_removed(timeout)?;
+                }
                 self.active = false;
                 Ok(())
             }
+            Err(e) if force && e.raw_os_error() == Some(EBUSY) => Err(e).with_context(|| {
+                format!(
+                    "Device {} is still busy after {} attempts",
+                    self.uuid.to_hyphenated(),
+                    attempts
+                )
+            }),
             Err(e) => Err(e).with_context(|| format!("Error removing device {:?}", self.uuid)),
         }
     }
 
-    pub fn create(&mut self) -> Result<()> {
+    // poll until the device directory is gone or `timeout` has passed
+    fn wait_removed(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.path().exists() {
+            if Instant::now() >= deadline {
+                return Err(anyhow!(
+                    "Device {} still exists {}s after removing it",
+                    self.uuid.to_hyphenated(),
+                    timeout.as_secs_f32()
+                ));
+            }
+            thread::sleep(STOP_POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    /// Create the device in sysfs. If a device with the same uuid already exists with the same
+    /// parent and type, its live attributes are compared against the desired ones. Differences
+    /// are logged as a warning, or reported as the error if `strict` is set.
+    ///
+    /// On success, the number of available instances of the type before and after creating the
+    /// device is returned, the latter if it could be read. A driver that doesn't count the new
+    /// device as used is warned about.
+    pub fn create(&mut self, strict: bool) -> Result<(i32, Option<i32>)> {
+        self.ensure_writable()?;
         debug!("Creating mdev {:?}", self.uuid);
+        check_mdev_support(self.env)?;
         let parent = self.parent()?;
         let mdev_type = self.mdev_type()?;
+        let names = self.env.sysfs_names();
         let mut existing = MDev::new(self.env, self.uuid);
 
         if existing.load_from_sysfs().is_ok() && existing.active {
-            if existing.parent != self.parent {
+            let (same_parent, same_type) = self.same_parent_and_type(&existing)?;
+            if !same_parent {
                 return Err(anyhow!("Device exists under different parent"));
             }
-            if existing.mdev_type != self.mdev_type {
+            if !same_type {
                 return Err(anyhow!("Device exists with different type"));
             }
+            let differences = existing.attr_differences(&self.resolved_attrs()?);
+            if !differences.is_empty() {
+                let msg = format!(
+                    "Device exists with different attributes: {}",
+                    differences.join(", ")
+                );
+                if strict {
+                    return Err(anyhow!(msg));
+                }
+                warn!("{}", msg);
+            }
             return Err(anyhow!("Device already exists"));
         }
 
         let mut path: PathBuf = self
             .env
             .parent_base()
-            .join(&parent)
-            .join("mdev_supported_types");
+            .join(parent)
+            .join(names.supported_types);
         debug!("Checking parent for mdev support: {:?}", path);
         if !path.is_dir() {
             return Err(anyhow!(
@@ -305,7 +611,7 @@ impl<'a> MDev<'a> {
                 parent
             ));
         }
-        path.push(&mdev_type);
+        path.push(mdev_type);
         debug!("Checking parent for mdev type {}: {:?}", mdev_type, path);
         if !path.is_dir() {
             return Err(anyhow!(
@@ -314,78 +620,508 @@ impl<'a> MDev<'a> {
                 mdev_type
             ));
         }
-        path.push("available_instances");
+        path.push(names.available_instances);
         debug!("Checking available instances: {:?}", path);
         let avail: i32 = fs::read_to_string(&path)?.trim().parse()?;
 
         debug!("Available instances: {}", avail);
         if avail == 0 {
-            return Err(anyhow!(
-                "No available instances of {} on {}",
-                mdev_type,
-                parent
-            ));
+            return Err(anyhow!(no_instances_message(self.env, parent, mdev_type)));
         }
+        let availpath = path.clone();
         path.pop();
-        path.push("create");
+        path.push(names.create);
         debug!("Creating mediated device: {:?} -> {:?}", self.uuid, path);
-        match fs::write(path, self.uuid.to_hyphenated().to_string()) {
-            Ok(_) => {
-                self.active = true;
-                Ok(())
-            }
-            Err(e) => Err(e).with_context(|| {
-                format!(
-                    "Failed to create mdev {}, type {} on {}",
-                    self.uuid.to_hyphenated().to_string(),
-                    mdev_type,
-                    parent
-                )
-            }),
+        fs::write(path, self.uuid.to_hyphenated().to_string()).with_context(|| {
+            format!(
+                "Failed to create mdev {}, type {} on {}",
+                self.uuid.to_hyphenated(),
+                mdev_type,
+                parent
+            )
+        })?;
+
+        // the count is informational, so failing to read it doesn't fail the creation
+        let after = fs::read_to_string(&availpath)
+            .ok()
+            .and_then(|s| s.trim().parse::<i32>().ok());
+        debug!(
+            "Available instances of {} on {} after creating {}: {} -> {:?}",
+            mdev_type,
+            parent,
+            self.uuid.to_hyphenated(),
+            avail,
+            after
+        );
+        if let Some(msg) = after.and_then(|after| instance_usage_problem(avail, after)) {
+            warn!("{} on {}: {}", mdev_type, parent, msg);
+        }
+        self.active = true;
+        Ok((avail, after))
+    }
+
+    /// Check that the device is specified well enough to be started, reporting everything that is
+    /// missing or malformed in a single error.
+    pub fn validate_for_start(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if self.parent.is_none() {
+            problems.push("no parent device".to_string());
+        }
+        if self.mdev_type.is_none() {
+            problems.push("no mdev type".to_string());
+        }
+        for (k, _) in self.attrs.iter().filter(|(k, _)| !valid_sysfs_name(k)) {
+            problems.push(format!("invalid attribute name '{}'", k));
+        }
+        problems.extend(self.api_problems());
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow!(
+                "Device {} cannot be started: {}",
+                self.uuid.to_hyphenated(),
+                problems.join(", ")
+            )),
+        }
+    }
+
+    /// Check the attributes of the device against the requirements of the device API of its type
+    /// (see [`api_validator`]). Nothing is checked if the device API can't be determined, e.g.
+    /// because the parent device isn't present.
+    pub fn api_problems(&self) -> Vec<String> {
+        match self.device_api() {
+            Ok(api) => api_validator(&api)(&self.attrs),
+            Err(_) => Vec::new(),
         }
     }
 
-    pub fn start(&mut self) -> Result<()> {
-        self.create()?;
+    /// Create the device and write its attributes. If `deadline` passes before all attributes are
+    /// written, the partially set up device is removed again and a timeout error is returned.
+    pub fn start(&mut self, strict: bool, deadline: Option<Instant>) -> Result<()> {
+        self.validate_for_start()?;
+        let attrs = self.resolved_attrs()?;
+        self.ensure_writable()?;
+        let expired = || deadline.is_some_and(|d| Instant::now() >= d);
+        let timed_out = |uuid: Uuid| anyhow!("Timed out starting device {}", uuid.to_hyphenated());
+        // another process may start the same device while waiting for the lock
+        let existed = self.path().exists();
+        let _lock =
+            lock_device(self.env, &self.uuid, deadline)?.ok_or_else(|| timed_out(self.uuid))?;
+        if !existed && self.started_concurrently()? {
+            return Ok(());
+        }
+        if expired() {
+            return Err(timed_out(self.uuid));
+        }
+        self.create(strict)?;
 
         debug!("Setting attributes for mdev {:?}", self.uuid);
-        for (k, v) in self.attrs.iter() {
-            if let Err(e) = write_attr(&self.path(), &k, &v) {
-                self.stop()?;
+        for (k, v) in attrs.iter() {
+            let res = match expired() {
+                true => Err(timed_out(self.uuid)),
+                false => self.write_attr(k, v),
+            };
+            if let Err(e) = res {
+                self.stop(false, None)?;
                 return Err(e);
             }
         }
+        // a device which only finished starting after the deadline is not left behind
+        if expired() {
+            self.stop(false, None)?;
+            return Err(timed_out(self.uuid));
+        }
 
         Ok(())
     }
 
+    // whether the parent and the type of this device are those of the active device `other`, which
+    // was loaded with their canonical names. The names of this device may be aliases of those.
+    fn same_parent_and_type(&self, other: &MDev) -> Result<(bool, bool)> {
+        let actual_parent = other.parent()?;
+        let typepath = other.path().join("mdev_type");
+        Ok((
+            self.is_parent_name(self.parent()?, actual_parent),
+            self.is_type_name(
+                self.mdev_type()?,
+                &typepath,
+                actual_parent,
+                other.mdev_type()?,
+            ),
+        ))
+    }
+
+    // whether the device was started by another process in the meantime with the same parent and
+    // type, in which case starting it again is not needed. A different parent or type is an error.
+    fn started_concurrently(&mut self) -> Result<bool> {
+        let mut other = MDev::new(self.env, self.uuid);
+        if other.load_from_sysfs().is_err() || !other.active {
+            return Ok(false);
+        }
+        if self.same_parent_and_type(&other)? != (true, true) {
+            return Err(anyhow!(
+                "Device {} was started concurrently by another process with parent {} and type {}",
+                self.uuid.to_hyphenated(),
+                other.parent.as_deref().unwrap_or("unknown"),
+                other.mdev_type.as_deref().unwrap_or("unknown")
+            ));
+        }
+        debug!(
+            "Device {} was started concurrently by another process",
+            self.uuid.to_hyphenated()
+        );
+        self.active = true;
+        Ok(true)
+    }
+
+    /// Check whether the device could be started without changing anything on the system, and
+    /// return a description of each problem found. An empty list means that the device is expected
+    /// to start.
+    pub fn verify(&self) -> Result<Vec<String>> {
+        let mut problems = Vec::new();
+        if self.active {
+            problems.push(format!(
+                "Device {} is already active",
+                self.uuid.to_hyphenated()
+            ));
+        }
+
+        let names = self.env.sysfs_names();
+        match (self.parent.as_ref(), self.mdev_type.as_ref()) {
+            (None, _) => problems.push("No parent device specified".to_string()),
+            (_, None) => problems.push("No mdev type specified".to_string()),
+            (Some(parent), Some(mdev_type)) => {
+                let types = self
+                    .env
+                    .parent_base()
+                    .join(parent)
+                    .join(names.supported_types);
+                let typedir = types.join(mdev_type);
+                if !types.is_dir() {
+                    problems.push(format!(
+                        "Parent {} is not currently registered for mdev support",
+                        parent
+                    ));
+                } else if !typedir.is_dir() {
+                    problems.push(format!(
+                        "Parent {} does not support mdev type {}",
+                        parent, mdev_type
+                    ));
+                } else {
+                    let path = typedir.join(names.available_instances);
+                    let avail: i32 = fs::read_to_string(&path)
+                        .with_context(|| format!("Failed to read {:?}", path))?
+                        .trim()
+                        .parse()
+                        .with_context(|| format!("Failed to parse {:?}", path))?;
+                    if avail == 0 {
+                        problems.push(no_instances_message(self.env, parent, mdev_type));
+                    }
+                }
+            }
+        }
+
+        for (k, _) in self.attrs.iter().filter(|(k, _)| !valid_sysfs_name(k)) {
+            problems.push(format!("Invalid attribute name '{}'", k));
+        }
+        for problem in self.api_problems() {
+            problems.push(format!(
+                "Invalid attributes for the device API: {}",
+                problem
+            ));
+        }
+        match self.resolved_attrs() {
+            Err(e) => problems.push(format!("{:#}", e)),
+            Ok(attrs) => {
+                for (k, v) in attrs.iter() {
+                    if v.len() > self.max_value_len(k, v) || v.contains('\0') {
+                        problems.push(format!("Invalid value for attribute '{}'", k));
+                    } else if MATRIX_ATTRS.contains(&k.as_str()) {
+                        if let Err(e) = expand_attr_list(v) {
+                            problems.push(format!("Invalid value for attribute '{}': {}", k, e));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Get the attributes in the order they are written when starting the device, with references
+    /// of the form `${name}` replaced by the value of an attribute set earlier in the sequence, or
+    /// by the uuid of the device for `${uuid}` unless an attribute of that name was set. `$$` is
+    /// written as a literal `$`. As only earlier attributes can be referred to, references can't
+    /// form a cycle; a reference to an attribute which is only set later is reported as such.
+    /// Unless the environment enables interpolation, the attributes are returned unchanged.
+    pub fn resolved_attrs(&self) -> Result<Vec<(String, String)>> {
+        if !self.env.interpolate_attrs() {
+            return Ok(self.attrs.clone());
+        }
+        let mut resolved: Vec<(String, String)> = Vec::new();
+        for (i, (k, v)) in self.attrs.iter().enumerate() {
+            let value = interpolate_attr(v, |name| {
+                if let Some((_, val)) = resolved.iter().rev().find(|(prev, _)| prev == name) {
+                    return Ok(val.clone());
+                }
+                if self.attrs[i..].iter().any(|(later, _)| later == name) {
+                    return Err(anyhow!(
+                        "Reference to attribute '{}' which is only set later, which could form a \
+                         cycle",
+                        name
+                    ));
+                }
+                match name {
+                    "uuid" => Ok(self.uuid.to_hyphenated().to_string()),
+                    _ => Err(anyhow!(
+                        "Reference to attribute '{}' which is not set before",
+                        name
+                    )),
+                }
+            })
+            .with_context(|| format!("Invalid value for attribute '{}'", k))?;
+            resolved.push((k.clone(), value));
+        }
+        Ok(resolved)
+    }
+
     pub fn write_config(&self) -> Result<()> {
-        let jsonstring = serde_json::to_string_pretty(&self.to_json(false)?)?;
+        self.parent()?;
         let path = self.persist_path().unwrap();
-        let parentdir = path.parent().unwrap();
+        match self.env.persist_layout() {
+            PersistLayout::PerDevice => self.write_config_to(&path),
+            PersistLayout::PerParent => {
+                self.ensure_writable()?;
+                let mut defs: Vec<(String, serde_json::Value)> =
+                    read_aggregate(&path)?.into_iter().collect();
+                let uuid = self.uuid.to_hyphenated().to_string();
+                defs.retain(|(k, _)| *k != uuid);
+                defs.push((uuid, self.to_json(false, false, false)?));
+                // keep entries sorted by uuid so that changes to the file are localized
+                defs.sort_by(|a, b| a.0.cmp(&b.0));
+                self.write_json(
+                    &path,
+                    &serde_json::Value::Object(defs.into_iter().collect()),
+                )
+            }
+        }
+    }
+
+    /// Write the definition of this device to `path` instead of its persistent location. The file
+    /// is replaced atomically, so readers never observe a partially written definition.
+    pub fn write_config_to(&self, path: &Path) -> Result<()> {
+        self.ensure_writable()?;
+        self.write_json(path, &self.to_json(false, false, false)?)
+    }
+
+    /// Atomically replace the file at `path` with `json`, creating its directory if necessary.
+    /// Unlike [`MDev::write_config_to`], this doesn't refuse to write in a read-only environment,
+    /// so it is also suitable for writing copies of definitions outside of the configuration.
+    pub fn write_json(&self, path: &Path, json: &serde_json::Value) -> Result<()> {
+        let jsonstring = serde_json::to_string_pretty(json)?;
+        let parentdir = path
+            .parent()
+            .ok_or_else(|| anyhow!("Invalid config path {:?}", path))?;
         debug!("Ensuring parent directory {:?} exists", parentdir);
-        fs::create_dir_all(parentdir)?;
+        if !parentdir.exists() {
+            fs::create_dir_all(parentdir)?;
+            fs::set_permissions(
+                parentdir,
+                fs::Permissions::from_mode(self.env.config_dir_mode()),
+            )
+            .with_context(|| format!("Failed to set permissions of {:?}", parentdir))?;
+        }
         debug!("Writing config for {:?} to {:?}", self.uuid, path);
-        fs::write(path, jsonstring.as_bytes())
-            .with_context(|| format!("Failed to write config for device {:?}", self.uuid))
+        let mut tmpfile = tempfile::NamedTempFile::new_in(parentdir)
+            .with_context(|| format!("Failed to create temporary file in {:?}", parentdir))?;
+        tmpfile
+            .write_all(jsonstring.as_bytes())
+            .with_context(|| format!("Failed to write config for device {:?}", self.uuid))?;
+        fs::set_permissions(
+            tmpfile.path(),
+            fs::Permissions::from_mode(self.env.config_file_mode()),
+        )
+        .with_context(|| format!("Failed to set permissions of {:?}", path))?;
+        tmpfile
+            .persist(path)
+            .with_context(|| format!("Failed to write config for device {:?}", self.uuid))?;
+        Ok(())
     }
 
     pub fn define(&self) -> Result<()> {
         self.write_config()
     }
 
+    /// Define the device, but only write its definition if it differs from the one stored, so
+    /// that defining an unchanged device again doesn't touch the file. Returns whether the
+    /// definition was written.
+    pub fn define_if_changed(&self) -> Result<bool> {
+        self.parent()?;
+        let path = self.persist_path().unwrap();
+        let json = self.to_json(false, false, false)?;
+        let unchanged = match self.env.persist_layout() {
+            PersistLayout::PerDevice => {
+                let jsonstring = serde_json::to_string_pretty(&json)?;
+                fs::read_to_string(&path).is_ok_and(|contents| contents == jsonstring)
+            }
+            PersistLayout::PerParent => read_aggregate(&path)?
+                .get(&self.uuid.to_hyphenated().to_string())
+                .is_some_and(|stored| *stored == json),
+        };
+        if unchanged {
+            debug!("Definition of {:?} in {:?} is unchanged", self.uuid, path);
+            return Ok(false);
+        }
+        self.write_config()?;
+        Ok(true)
+    }
+
     pub fn undefine(&mut self) -> Result<()> {
-        let p = self.persist_path().ok_or_else(|| {
-            anyhow!(
-                "Failed to undefine {}",
-                self.uuid.to_hyphenated().to_string()
-            )
-        })?;
+        self.ensure_writable()?;
+        let p = self
+            .persist_path()
+            .ok_or_else(|| anyhow!("Failed to undefine {}", self.uuid.to_hyphenated()))?;
+
+        if self.env.persist_layout() == PersistLayout::PerParent {
+            let mut defs = read_aggregate(&p)?;
+            if defs
+                .remove(&self.uuid.to_hyphenated().to_string())
+                .is_none()
+            {
+                return Err(anyhow!(
+                    "Failed to undefine {}: no entry in {:?}",
+                    self.uuid.to_hyphenated(),
+                    p
+                ));
+            }
+            if !defs.is_empty() {
+                return self.write_json(&p, &serde_json::Value::Object(defs));
+            }
+        }
 
         fs::remove_file(&p).with_context(|| format!("Failed to remove file {:?}", p))?;
         Ok(())
     }
 
+    // the maximum length of `val` when written to the attribute `attr`, since a newline appended
+    // to the value counts towards the length of the write
+    fn max_value_len(&self, attr: &str, val: &str) -> usize {
+        match self.env.attr_newline(attr) {
+            AttrNewline::Append if !val.ends_with('\n') => {
+                self.env.max_attr_len().saturating_sub(1)
+            }
+            _ => self.env.max_attr_len(),
+        }
+    }
+
+    /// Write `val` to the sysfs attribute `attr` of the device. The value of a vfio-ap matrix
+    /// attribute (see [`MATRIX_ATTRS`]) may be a list such as `0x0a,0x0b,0x10-0x12`, which is
+    /// expanded and written one element at a time. A newline is appended to each value written,
+    /// unless the environment's [`AttrNewline`] policy for the attribute says otherwise.
+    pub fn write_attr(&self, attr: &str, val: &str) -> Result<()> {
+        self.ensure_writable()?;
+        debug!("Writing attribute '{}' -> '{}'", attr, val);
+        let path = self.path().join(attr);
+        if !path.exists() {
+            return Err(anyhow!("Invalid attribute '{}'", attr));
+        }
+        // a subdirectory of the device, such as 'power', can share the name of an attribute
+        if path.is_dir() {
+            return Err(anyhow!(
+                "Attribute '{}' is not writable (it is a directory)",
+                attr
+            ));
+        }
+        let newline = self.env.attr_newline(attr);
+        let max_len = self.max_value_len(attr, val);
+        if val.len() > max_len {
+            return Err(anyhow!(
+                "Value for attribute '{}' is too long ({} bytes, maximum {})",
+                attr,
+                val.len(),
+                max_len
+            ));
+        }
+        if val.contains('\0') {
+            return Err(anyhow!(
+                "Value for attribute '{}' contains a NUL character",
+                attr
+            ));
+        }
+        let values = if MATRIX_ATTRS.contains(&attr) {
+            expand_attr_list(val)
+                .with_context(|| format!("Invalid value for attribute '{}'", attr))?
+        } else {
+            vec![val.to_string()]
+        };
+        for v in values.iter() {
+            let data = match newline {
+                AttrNewline::Append if !v.ends_with('\n') => format!("{}\n", v),
+                _ => v.to_string(),
+            };
+            fs::write(&path, data)
+                .with_context(|| format!("Failed to write {} to attribute {}", v, attr))?;
+        }
+        Ok(())
+    }
+
+    /// Apply changes between the definition `old` and this device to the running device on a
+    /// best-effort basis. Attributes that were added are written to sysfs in order. Any change that
+    /// cannot be applied to a running device (a changed type, removed or reordered attributes, or
+    /// a failed attribute write) is returned as a description of a change that requires the device
+    /// to be restarted.
+    pub fn apply_live(&self, old: &MDev) -> Vec<String> {
+        let mut restart = Vec::new();
+        if self.mdev_type != old.mdev_type {
+            restart.push(format!(
+                "mdev type changed from {} to {}",
+                old.mdev_type.as_deref().unwrap_or("<none>"),
+                self.mdev_type.as_deref().unwrap_or("<none>")
+            ));
+        }
+
+        let mut removed = old.attrs.clone();
+        let mut added = Vec::new();
+        for (i, attr) in self.attrs.iter().enumerate() {
+            match removed.iter().position(|a| a == attr) {
+                Some(j) => {
+                    removed.remove(j);
+                }
+                None => added.push(i),
+            }
+        }
+
+        for (k, v) in removed.iter() {
+            restart.push(format!("attribute {}={} removed", k, v));
+        }
+        if added.is_empty() && removed.is_empty() && self.attrs != old.attrs {
+            restart.push("attribute order changed".to_string());
+        }
+
+        // added attributes are written with the values they would get when starting the device
+        let resolved = match added.is_empty() {
+            true => Vec::new(),
+            false => match self.resolved_attrs() {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    restart.push(format!("attributes could not be applied: {:#}", e));
+                    return restart;
+                }
+            },
+        };
+        for i in added {
+            let (k, v) = &resolved[i];
+            debug!(
+                "Applying attribute {}={} to running device {:?}",
+                k, v, self.uuid
+            );
+            if let Err(e) = self.write_attr(k, v) {
+                debug!("{:?}", e);
+                restart.push(format!("attribute {}={} could not be applied: {}", k, v, e));
+            }
+        }
+
+        restart
+    }
+
     pub fn add_attribute(&mut self, name: String, value: String, index: Option<u32>) -> Result<()> {
         match index {
             Some(i) => {
@@ -417,6 +1153,420 @@ impl<'a> MDev<'a> {
 
         Ok(())
     }
+
+    /// Check whether the device has an attribute matching `pattern`, which is either `KEY=VALUE`
+    /// to match an attribute with the given name and value, or `KEY` to match an attribute with
+    /// the given name and any value.
+    pub fn has_attr(&self, pattern: &str) -> bool {
+        match pattern.split_once('=') {
+            Some((key, value)) => self.attrs.iter().any(|(k, v)| k == key && v == value),
+            None => self.attrs.iter().any(|(k, _)| k == pattern),
+        }
+    }
+
+    /// Remove the first attribute named `name`, or every attribute with that name if `all` is set.
+    /// Returns the number of attributes removed.
+    pub fn delete_attribute_by_name(&mut self, name: &str, all: bool) -> Result<usize> {
+        let before = self.attrs.len();
+        if all {
+            self.attrs.retain(|(k, _)| k != name);
+        } else if let Some(i) = self.attrs.iter().position(|(k, _)| k == name) {
+            self.attrs.remove(i);
+        }
+
+        match before - self.attrs.len() {
+            0 => Err(anyhow!("No attribute named '{}'", name)),
+            n => Ok(n),
+        }
+    }
+
+    /// Remove attributes which repeat both the name and the value of an earlier attribute,
+    /// keeping the first occurrence of each in place. Attributes which share a name but have
+    /// different values are kept. Returns the number of attributes removed.
+    pub fn dedup_attributes(&mut self) -> usize {
+        let before = self.attrs.len();
+        let mut seen = HashSet::new();
+        self.attrs.retain(|attr| seen.insert(attr.clone()));
+        before - self.attrs.len()
+    }
+}
+
+/// The definition of a device in the shape it is stored in json: its type, its startup mode as
+/// "auto" or "manual", and its attributes as an array of single-field objects in the order they
+/// are written. This is the one place converting between that shape and the fields of [`MDev`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub mdev_type: String,
+    pub autostart: bool,
+    pub attrs: Vec<(String, String)>,
+}
+
+impl DeviceConfig {
+    /// Read a definition from json. A missing or malformed "attrs" field is treated as having no
+    /// attributes.
+    pub fn from_json(json: &serde_json::Value) -> Result<DeviceConfig> {
+        let (mdev_type, start) = match (json["mdev_type"].as_str(), json["start"].as_str()) {
+            (Some(mdev_type), Some(start)) => (mdev_type, start),
+            _ => return Err(anyhow!("invalid json")),
+        };
+        let mut attrs = Vec::new();
+        for attr in json["attrs"].as_array().into_iter().flatten() {
+            let attrobj = attr
+                .as_object()
+                .ok_or_else(|| anyhow!("invalid JSON format for attribute: not an object"))?;
+            // attributes are represented by JSON objects with a single field.
+            if attrobj.len() != 1 {
+                return Err(anyhow!(
+                    "invalid JSON format for attribute: too many fields"
+                ));
+            }
+            // get the key and value from the first (only) map entry
+            if let Some((key, val)) = attrobj.iter().next() {
+                let valstr = val.as_str().ok_or_else(|| {
+                    anyhow!(
+                        "invalid JSON format for attribute '{}': value is not a string",
+                        key
+                    )
+                })?;
+                attrs.push((key.to_string(), valstr.to_string()));
+            }
+        }
+        Ok(DeviceConfig {
+            mdev_type: mdev_type.to_string(),
+            autostart: start == "auto",
+            attrs,
+        })
+    }
+
+    /// Get the definition as a json object with the fields in the order they are stored.
+    pub fn to_json(&self) -> serde_json::Map<String, serde_json::Value> {
+        let start = match self.autostart {
+            true => "auto",
+            false => "manual",
+        };
+        let attrs: Vec<serde_json::Value> = self
+            .attrs
+            .iter()
+            .map(|(key, value)| serde_json::json!({ key: value }))
+            .collect();
+        let mut obj = serde_json::Map::new();
+        obj.insert("mdev_type".to_string(), self.mdev_type.clone().into());
+        obj.insert("start".to_string(), start.into());
+        obj.insert("attrs".to_string(), attrs.into());
+        obj
+    }
+}
+
+impl TryFrom<&MDev<'_>> for DeviceConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(dev: &MDev) -> Result<DeviceConfig> {
+        Ok(DeviceConfig {
+            mdev_type: dev.mdev_type()?.clone(),
+            autostart: dev.autostart,
+            attrs: dev.attrs.clone(),
+        })
+    }
+}
+
+/// Build a device from its definition along with the environment, uuid and parent of the device,
+/// which the definition doesn't hold itself. The parent, type and attribute names must each name
+/// a single sysfs entry.
+impl<'a> TryFrom<(&'a dyn Environment, Uuid, String, DeviceConfig)> for MDev<'a> {
+    type Error = anyhow::Error;
+
+    fn try_from(
+        (env, uuid, parent, config): (&'a dyn Environment, Uuid, String, DeviceConfig),
+    ) -> Result<MDev<'a>> {
+        if !valid_sysfs_name(&parent) {
+            return Err(anyhow!("Invalid parent device '{}'", parent));
+        }
+        if !valid_sysfs_name(&config.mdev_type) {
+            return Err(anyhow!("Invalid mdev type '{}'", config.mdev_type));
+        }
+        if let Some((k, _)) = config.attrs.iter().find(|(k, _)| !valid_sysfs_name(k)) {
+            return Err(anyhow!("Invalid attribute name '{}'", k));
+        }
+        let mut dev = MDev::new(env, uuid);
+        dev.parent = Some(parent);
+        dev.mdev_type = Some(config.mdev_type);
+        dev.autostart = config.autostart;
+        dev.attrs = config.attrs;
+        Ok(dev)
+    }
+}
+
+/// Unwrap a device definition stored in the legacy format of an object with a single field named
+/// by the device's uuid. Returns the definition and whether it was in the legacy format.
+pub fn unwrap_legacy_json(uuid: &Uuid, val: serde_json::Value) -> (serde_json::Value, bool) {
+    let key = uuid.to_hyphenated().to_string();
+    match val {
+        serde_json::Value::Object(mut obj) if obj.len() == 1 && obj[&key].is_object() => {
+            (obj.remove(&key).unwrap(), true)
+        }
+        val => (val, false),
+    }
+}
+
+/// Read a file holding the definitions of all devices of a parent, as used by
+/// [`PersistLayout::PerParent`]. A file that doesn't exist holds no definitions.
+pub fn read_aggregate(path: &Path) -> Result<serde_json::Map<String, serde_json::Value>> {
+    if !path.exists() {
+        return Ok(serde_json::Map::new());
+    }
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("reading definitions {:?}", path))?;
+    match serde_json::from_str(&contents)
+        .with_context(|| format!("parsing definitions {:?}", path))?
+    {
+        serde_json::Value::Object(defs) => Ok(defs),
+        _ => Err(anyhow!("parsing definitions {:?}: not a json object", path)),
+    }
+}
+
+// substitute `${name}` references in an attribute value using `lookup`, and `$$` with `$`. A `$`
+// followed by anything else is kept as is.
+fn interpolate_attr<F: Fn(&str) -> Result<String>>(value: &str, lookup: F) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = value;
+    while let Some(pos) = rest.find('$') {
+        output.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        if let Some(stripped) = rest.strip_prefix('$') {
+            output.push('$');
+            rest = stripped;
+        } else if let Some(stripped) = rest.strip_prefix('{') {
+            let end = stripped
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated reference in '{}'", value))?;
+            let name = &stripped[..end];
+            output.push_str(&lookup(name)?);
+            rest = &stripped[end + 1..];
+        } else {
+            output.push('$');
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+// whether `name` can name a single entry of a sysfs directory, such as a parent device, a type of
+// a parent, or an attribute of a device
+fn valid_sysfs_name(name: &str) -> bool {
+    !(name.is_empty() || name == "." || name == ".." || name.contains('/'))
+}
+
+/// A function checking the attributes of a device against the requirements of a device API,
+/// returning a description of each problem found
+pub type ApiValidator = fn(&[(String, String)]) -> Vec<String>;
+
+/// Get the [`ApiValidator`] for devices of a type with the given `device_api`. Device APIs without
+/// specific requirements accept any attributes.
+pub fn api_validator(device_api: &str) -> ApiValidator {
+    match device_api {
+        "vfio-ap" => validate_vfio_ap,
+        "vfio-ccw" => validate_vfio_ccw,
+        _ => validate_any,
+    }
+}
+
+fn validate_any(_attrs: &[(String, String)]) -> Vec<String> {
+    Vec::new()
+}
+
+// a vfio-ap device gives a guest access to the AP queues at the intersection of its adapters and
+// domains, so without at least one of each it is of no use
+fn validate_vfio_ap(attrs: &[(String, String)]) -> Vec<String> {
+    ["assign_adapter", "assign_domain"]
+        .iter()
+        .filter(|name| !attrs.iter().any(|(k, _)| k == *name))
+        .map(|name| format!("vfio-ap devices require at least one '{}' attribute", name))
+        .collect()
+}
+
+// vfio-ccw devices are configured entirely through their parent subchannel
+fn validate_vfio_ccw(attrs: &[(String, String)]) -> Vec<String> {
+    attrs
+        .iter()
+        .map(|(k, _)| format!("vfio-ccw devices don't support attribute '{}'", k))
+        .collect()
+}
+
+/// Attributes of vfio-ap devices that accept a single adapter or domain number per write, but
+/// whose value in a definition may be a list of numbers and ranges.
+pub const MATRIX_ATTRS: [&str; 6] = [
+    "assign_adapter",
+    "assign_domain",
+    "assign_control_domain",
+    "unassign_adapter",
+    "unassign_domain",
+    "unassign_control_domain",
+];
+
+/// The highest adapter or domain number in a vfio-ap matrix
+const MAX_MATRIX_INDEX: u32 = 0xff;
+
+/// Expand a comma-separated list of numbers and inclusive ranges such as `0x0a,0x0b,0x10-0x12`
+/// into its elements. Elements that aren't ranges are kept as written, the elements of a range are
+/// written in hexadecimal if its start is, and in decimal otherwise. Ranges must lie within the
+/// matrix and a list expands to at most as many elements as the matrix has indexes.
+pub fn expand_attr_list(value: &str) -> Result<Vec<String>> {
+    let mut values = Vec::new();
+    for item in value.split(',').map(str::trim) {
+        match item.split_once('-') {
+            None => {
+                parse_attr_number(item)?;
+                values.push(item.to_string());
+            }
+            Some((start, end)) => {
+                let (start, end) = (start.trim(), end.trim());
+                let first = parse_attr_number(start)?;
+                let last = parse_attr_number(end)?;
+                if first > last {
+                    return Err(anyhow!("Invalid range '{}': start is after end", item));
+                }
+                if last > MAX_MATRIX_INDEX {
+                    return Err(anyhow!(
+                        "Invalid range '{}': end is above {:#x}",
+                        item,
+                        MAX_MATRIX_INDEX
+                    ));
+                }
+                if values.len() + (last - first + 1) as usize > MAX_MATRIX_INDEX as usize + 1 {
+                    return Err(anyhow!(
+                        "Invalid list '{}': more than {} elements",
+                        value,
+                        MAX_MATRIX_INDEX + 1
+                    ));
+                }
+                let hex = start.starts_with("0x") || start.starts_with("0X");
+                values.extend((first..=last).map(|n| match hex {
+                    true => format!("{:#x}", n),
+                    false => n.to_string(),
+                }));
+            }
+        }
+    }
+    Ok(values)
+}
+
+// parse a decimal or `0x`-prefixed hexadecimal number in a matrix attribute list
+fn parse_attr_number(s: &str) -> Result<u32> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse::<u32>(),
+    };
+    parsed.map_err(|_| anyhow!("Invalid number '{}'", s))
+}
+
+/// Get the number of active devices of `mdev_type` on `parent`, or None if the active devices
+/// can't be looked up.
+fn instances_in_use(env: &dyn Environment, parent: &str, mdev_type: &str) -> Option<usize> {
+    let dir = env.mdev_base().read_dir().ok()?;
+    let count = dir
+        .filter_map(|e| e.ok())
+        .filter_map(|e| Uuid::parse_str(e.file_name().to_str()?).ok())
+        .filter(|u| {
+            let mut dev = MDev::new(env, *u);
+            dev.load_from_sysfs().is_ok()
+                && dev.active
+                && dev.parent.as_deref() == Some(parent)
+                && dev.mdev_type.as_deref() == Some(mdev_type)
+        })
+        .count();
+    Some(count)
+}
+
+/// Describe the lack of available instances of `mdev_type` on `parent`, including how many
+/// instances of that type are in use for capacity planning if that can be found out. No total is
+/// given, since the capacity of a parent may be shared with its other types.
+fn no_instances_message(env: &dyn Environment, parent: &str, mdev_type: &str) -> String {
+    let msg = format!("No available instances of {} on {}", mdev_type, parent);
+    match instances_in_use(env, parent, mdev_type) {
+        Some(used) => format!("{} (0 available; {} in use)", msg, used),
+        None => msg,
+    }
+}
+
+/// Check the number of available instances of a type `before` and `after` creating a single
+/// device of that type, returning a description of the problem if the driver didn't count the new
+/// device as used.
+pub fn instance_usage_problem(before: i32, after: i32) -> Option<String> {
+    match after.cmp(&before) {
+        std::cmp::Ordering::Less => None,
+        _ => Some(format!(
+            "available instances did not decrease after creating a device ({} -> {})",
+            before, after
+        )),
+    }
+}
+
+// number of attempts and the delay between them when forcing removal of a busy device
+const FORCE_REMOVE_ATTEMPTS: u32 = 10;
+const FORCE_REMOVE_DELAY: Duration = Duration::from_millis(100);
+const EBUSY: i32 = 16;
+const EAGAIN: i32 = 11;
+
+// how often to try to take the lock of a device when waiting for it until a deadline
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// how often to check whether a removed device is gone when waiting for it
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// call `op` up to `attempts` times, sleeping `delay` in between, for as long as it fails because
+// the device is busy
+pub fn retry_busy<F>(attempts: u32, delay: Duration, mut op: F) -> io::Result<()>
+where
+    F: FnMut() -> io::Result<()>,
+{
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(e) if e.raw_os_error() == Some(EBUSY) && attempt < attempts => {
+                debug!("Device busy, retrying ({}/{})", attempt, attempts);
+                attempt += 1;
+                thread::sleep(delay);
+            }
+            res => return res,
+        }
+    }
+}
+
+// whether `e` was caused by a sysfs write which failed because the device or driver was busy, so
+// the same operation may succeed when attempted again
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| matches!(e.raw_os_error(), Some(EBUSY) | Some(EAGAIN)))
+}
+
+// call `op` until it succeeds, retrying it up to `retries` more times after sleeping `delay` when
+// it fails with a transient error. Any other error is returned immediately, as is any error once
+// `deadline` has passed.
+pub fn retry_op<T, F>(
+    retries: u32,
+    delay: Duration,
+    deadline: Option<Instant>,
+    mut op: F,
+) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e)
+                if is_transient(&e)
+                    && attempt < retries
+                    && deadline.is_none_or(|d| Instant::now() + delay < d) =>
+            {
+                attempt += 1;
+                warn!("{:#}; retrying ({}/{})", e, attempt, retries);
+                thread::sleep(delay);
+            }
+            res => return res,
+        }
+    }
 }
 
 fn canonical_basename<P: AsRef<Path>>(path: P) -> Result<String> {
@@ -432,16 +1582,132 @@ fn canonical_basename<P: AsRef<Path>>(path: P) -> Result<String> {
     }
 }
 
-fn write_attr(basepath: &Path, attr: &str, val: &str) -> Result<()> {
-    debug!("Writing attribute '{}' -> '{}'", attr, val);
-    let path = basepath.join(attr);
-    if !path.exists() {
-        return Err(anyhow!("Invalid attribute '{}'", attr));
+/// Take the lock serializing operations on the device `uuid` between processes, waiting for any
+/// other process holding it. The lock is released when the returned file is dropped. If a
+/// `deadline` is given, `None` is returned once it passes without getting the lock.
+pub fn lock_device(
+    env: &dyn Environment,
+    uuid: &Uuid,
+    deadline: Option<Instant>,
+) -> Result<Option<fs::File>> {
+    let dir = env.lock_base();
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    let path = dir.join(format!("{}.lock", uuid.to_hyphenated()));
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open lock file {:?}", path))?;
+    debug!("Waiting for lock {:?}", path);
+    let deadline = match deadline {
+        Some(d) => d,
+        None => {
+            file.lock()
+                .with_context(|| format!("Failed to lock {:?}", path))?;
+            return Ok(Some(file));
+        }
+    };
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(Some(file)),
+            Err(fs::TryLockError::WouldBlock) => {}
+            Err(fs::TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {:?}", path))
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            debug!("Timed out waiting for lock {:?}", path);
+            return Ok(None);
+        }
+        thread::sleep(LOCK_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Check that the kernel supports mediated devices at all. Neither the mdev bus nor the class of
+/// parent devices exists in sysfs if it was built without support, in which case every path
+/// below them would fail with a confusing error.
+pub fn check_mdev_support(env: &dyn Environment) -> Result<()> {
+    if !env.parent_base().is_dir() && !env.mdev_base().is_dir() {
+        debug!(
+            "Neither {:?} nor {:?} exists",
+            env.parent_base(),
+            env.mdev_base()
+        );
+        return Err(anyhow!(
+            "This kernel does not appear to support mediated devices"
+        ));
+    }
+    Ok(())
+}
+
+/// Get the number of available instances of `mdev_type` on the parent device `parent`, reading
+/// only that value rather than every property of the type.
+pub fn count_available(env: &dyn Environment, parent: &str, mdev_type: &str) -> Result<u32> {
+    check_mdev_support(env)?;
+    let names = env.sysfs_names();
+    let mut path = env.parent_base().join(parent).join(names.supported_types);
+    if !path.is_dir() {
+        return Err(anyhow!(
+            "Parent {} is not currently registered for mdev support",
+            parent
+        ));
+    }
+    path.push(mdev_type);
+    if !path.is_dir() {
+        return Err(anyhow!(
+            "Parent {} does not support mdev type {}",
+            parent,
+            mdev_type
+        ));
+    }
+    path.push(names.available_instances);
+    debug!("Reading available instances: {:?}", path);
+    fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {:?}", path))?
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid number of available instances in {:?}", path))
+}
+
+/// Look up the parent device `name` in sysfs and get its canonical name, following it if it is a
+/// symlink to another parent device. The error lists the available parent devices if `name` is
+/// not one of them.
+pub fn resolve_parent(env: &dyn Environment, name: &str) -> Result<String> {
+    let base = env.parent_base();
+    let path = base.join(name);
+    if valid_sysfs_name(name) && path.exists() {
+        return canonical_basename(&path)
+            .with_context(|| format!("Failed to resolve parent device {}", name));
+    }
+
+    let mut parents: Vec<String> = match base.read_dir() {
+        Ok(dir) => dir
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    parents.sort();
+    match parents.is_empty() {
+        true => Err(anyhow!(
+            "Parent device {} not found: no parent devices available",
+            name
+        )),
+        false => Err(anyhow!(
+            "Parent device {} not found. Available parent devices: {}",
+            name,
+            parents.join(", ")
+        )),
     }
-    fs::write(path, val).with_context(|| format!("Failed to write {} to attribute {}", val, attr))
 }
 
 /// Representation of a mediated device type
+///
+/// Types compare equal if they have the same name, device API, and human readable name and
+/// description, regardless of the parent device providing them and the number of available
+/// instances, so that the capabilities of different hosts can be compared.
 #[derive(Debug, Clone)]
 pub struct MDevType {
     pub parent: String,
@@ -464,6 +1730,24 @@ impl MDevType {
         }
     }
 
+    /// Load a type named `typename` provided by `parent` from its json representation, as produced
+    /// by [`MDevType::to_json`].
+    pub fn from_json(parent: &str, typename: &str, val: &serde_json::Value) -> Result<MDevType> {
+        let field = |name: &str| val[name].as_str().unwrap_or_default().to_string();
+        let available_instances = val["available_instances"]
+            .as_i64()
+            .and_then(|n| n.try_into().ok())
+            .ok_or_else(|| anyhow!("Invalid available instances for type {}", typename))?;
+        Ok(MDevType {
+            parent: parent.to_string(),
+            typename: typename.to_string(),
+            available_instances,
+            device_api: field("device_api"),
+            name: field("name"),
+            description: field("description"),
+        })
+    }
+
     pub fn to_json(&self) -> Result<serde_json::Value> {
         let mut jsonobj = serde_json::json!({
             "available_instances": self.available_instances,
@@ -485,3 +1769,115 @@ impl MDevType {
         Ok(serde_json::json!({ &self.typename: jsonobj }))
     }
 }
+
+/// A function used to render the raw description of a mediated device type for display
+pub type DescriptionFormatter = fn(&str) -> String;
+
+/// The default [`DescriptionFormatter`], which displays the description as provided by the driver
+pub fn humanize_description(raw: &str) -> String {
+    raw.to_string()
+}
+
+/// A [`DescriptionFormatter`] which converts `key=NNNN` values of at least one MiB, which are
+/// assumed to be sizes in bytes, into MiB or GiB.
+pub fn humanize_byte_sizes(raw: &str) -> String {
+    const MIB: u64 = 1 << 20;
+    const GIB: u64 = 1 << 30;
+
+    static SIZE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"=(\d+)\b").unwrap());
+    SIZE.replace_all(raw, |caps: &regex::Captures| {
+        let size = match caps[1].parse::<u64>() {
+            Ok(n) if n >= GIB => format_size(n, GIB, "GiB"),
+            Ok(n) if n >= MIB => format_size(n, MIB, "MiB"),
+            _ => caps[1].to_string(),
+        };
+        format!("={}", size)
+    })
+    .to_string()
+}
+
+fn format_size(n: u64, unit: u64, suffix: &str) -> String {
+    match n % unit {
+        0 => format!("{} {}", n / unit, suffix),
+        _ => format!("{:.1} {}", n as f64 / unit as f64, suffix),
+    }
+}
+
+impl MDevType {
+    // the fields which identify the capabilities of a type
+    fn key(&self) -> (&str, &str, &str, &str) {
+        (
+            &self.typename,
+            &self.device_api,
+            &self.name,
+            &self.description,
+        )
+    }
+}
+
+impl PartialEq for MDevType {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for MDevType {}
+
+impl std::hash::Hash for MDevType {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+/// Get the types in `a` which are not in `b`, in the order of `a`
+pub fn type_set_difference(a: &[MDevType], b: &[MDevType]) -> Vec<MDevType> {
+    let b: HashSet<&MDevType> = b.iter().collect();
+    a.iter().filter(|t| !b.contains(t)).cloned().collect()
+}
+
+/// Read a collection of mediated device types from a json document as written by
+/// [`write_types_json`].
+pub fn read_types_json(val: &serde_json::Value) -> Result<Vec<MDevType>> {
+    let invalid = || anyhow!("Invalid mdev types json");
+    let mut types = Vec::new();
+    for parents in val.as_array().ok_or_else(invalid)? {
+        for (parent, children) in parents.as_object().ok_or_else(invalid)? {
+            for child in children.as_array().ok_or_else(invalid)? {
+                for (typename, t) in child.as_object().ok_or_else(invalid)? {
+                    types.push(MDevType::from_json(parent, typename, t)?);
+                }
+            }
+        }
+    }
+    Ok(types)
+}
+
+/// Write a collection of mediated device types as a json document. Types are grouped by parent
+/// and sorted by type name within each parent. Empty optional fields are omitted.
+pub fn write_types_json(types: &[MDevType], w: &mut dyn Write, pretty: bool) -> Result<()> {
+    let mut grouped: BTreeMap<&str, Vec<&MDevType>> = BTreeMap::new();
+    for t in types {
+        grouped.entry(&t.parent).or_default().push(t);
+    }
+
+    let mut parents = serde_json::map::Map::new();
+    for (parent, mut children) in grouped {
+        children.sort_by(|a, b| a.typename.cmp(&b.typename));
+        let mut childarray = Vec::new();
+        for child in children {
+            childarray.push(child.to_json()?);
+        }
+        parents.insert(parent.to_string(), childarray.into());
+    }
+
+    // don't serialize an empty object if there are no types
+    let jsonval = match parents.len() {
+        0 => serde_json::json!([]),
+        _ => serde_json::json!([parents]),
+    };
+    match pretty {
+        true => serde_json::to_writer_pretty(w, &jsonval),
+        false => serde_json::to_writer(w, &jsonval),
+    }
+    .map_err(|_e| anyhow!("Unable to serialize json"))
+}
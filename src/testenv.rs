@@ -0,0 +1,259 @@
+//! A filesystem environment for use by tests
+
+use anyhow::Result;
+use log::info;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::Builder;
+use tempfile::TempDir;
+use uuid::Uuid;
+
+use crate::environment::{AttrNewline, Environment, PersistLayout, SysfsNames, SYSFS_NAMES};
+use crate::mdev::MDev;
+
+const TEST_DATA_DIR: &str = "tests";
+
+/// An [`Environment`] for tests, rooted in a temporary directory which is removed when the
+/// environment is dropped.
+///
+/// The environment is associated with a directory of test data below `tests/`, which provides
+/// device definitions and expected output. Parent devices, supported types, active devices and
+/// definitions can be seeded with the `populate_*` methods or the chainable `with_*` builder
+/// methods.
+#[derive(Debug)]
+pub struct TestEnvironment {
+    /// directory containing the data files for the test
+    pub datapath: PathBuf,
+    scratch: TempDir,
+    /// value reported by [`Environment::read_only`]
+    pub read_only: bool,
+    /// values reported by [`Environment::config_dir_mode`] and [`Environment::config_file_mode`]
+    pub config_modes: (u32, u32),
+    /// value reported by [`Environment::sysfs_names`]
+    pub sysfs_names: SysfsNames,
+    /// value reported by [`Environment::persist_layout`]
+    pub persist_layout: PersistLayout,
+    /// attributes for which [`Environment::attr_newline`] reports [`AttrNewline::Raw`]
+    pub raw_attrs: Vec<String>,
+    /// value reported by [`Environment::max_attr_len`]
+    pub max_attr_len: usize,
+    /// value reported by [`Environment::interpolate_attrs`]
+    pub interpolate_attrs: bool,
+}
+
+impl Environment for TestEnvironment {
+    fn root(&self) -> &Path {
+        self.scratch.path()
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn config_dir_mode(&self) -> u32 {
+        self.config_modes.0
+    }
+
+    fn config_file_mode(&self) -> u32 {
+        self.config_modes.1
+    }
+
+    fn sysfs_names(&self) -> SysfsNames {
+        self.sysfs_names
+    }
+
+    fn persist_layout(&self) -> PersistLayout {
+        self.persist_layout
+    }
+
+    fn max_attr_len(&self) -> usize {
+        self.max_attr_len
+    }
+
+    fn attr_newline(&self, attr: &str) -> AttrNewline {
+        AttrNewline::for_attr(&self.raw_attrs, attr)
+    }
+
+    fn interpolate_attrs(&self) -> bool {
+        self.interpolate_attrs
+    }
+}
+
+impl TestEnvironment {
+    pub fn new(testname: &str, testcase: &str) -> TestEnvironment {
+        let path: PathBuf = [TEST_DATA_DIR, testname].iter().collect();
+        let scratchdir = Builder::new().prefix("mdevctl-test").tempdir().unwrap();
+        let test = TestEnvironment {
+            datapath: path,
+            scratch: scratchdir,
+            read_only: false,
+            config_modes: (0o755, 0o644),
+            sysfs_names: SYSFS_NAMES,
+            persist_layout: PersistLayout::PerDevice,
+            raw_attrs: Vec::new(),
+            max_attr_len: 4096,
+            interpolate_attrs: false,
+        };
+        // populate the basic directories in the environment
+        fs::create_dir_all(test.mdev_base()).expect("Unable to create mdev_base");
+        fs::create_dir_all(test.persist_base()).expect("Unable to create persist_base");
+        fs::create_dir_all(test.parent_base()).expect("Unable to create parent_base");
+        info!("---- Running test '{}/{}' ----", testname, testcase);
+        test
+    }
+
+    /// Seed the environment with a parent device supporting `mdev_type`, with a single available
+    /// instance and a `vfio-pci` device API.
+    pub fn with_parent(self, parent: &str, mdev_type: &str) -> TestEnvironment {
+        self.populate_parent_device(parent, mdev_type, 1, "vfio-pci", "", None);
+        self
+    }
+
+    /// Seed the environment with an active device.
+    pub fn with_active_device(self, uuid: &str, parent: &str, mdev_type: &str) -> TestEnvironment {
+        self.populate_active_device(uuid, parent, mdev_type);
+        self
+    }
+
+    /// Seed the environment with a device definition given as json.
+    pub fn with_definition(
+        self,
+        uuid: &str,
+        parent: &str,
+        json: &serde_json::Value,
+    ) -> TestEnvironment {
+        let parentdir = self.persist_base().join(parent);
+        fs::create_dir_all(&parentdir).expect("Unable to setup parent dir");
+        let deffile = parentdir.join(uuid);
+        assert!(!deffile.exists());
+        fs::write(deffile, serde_json::to_string_pretty(json).unwrap())
+            .expect("Unable to write device def");
+        self
+    }
+
+    // set up a few files in the test environment to simulate an defined mediated device
+    pub fn populate_defined_device(&self, uuid: &str, parent: &str, filename: &str) {
+        let jsonfile = self.datapath.join(filename);
+        let parentdir = self.persist_base().join(parent);
+        fs::create_dir_all(&parentdir).expect("Unable to setup parent dir");
+        let deffile = parentdir.join(uuid);
+        assert!(jsonfile.exists());
+        assert!(!deffile.exists());
+        fs::copy(jsonfile, deffile).expect("Unable to copy device def");
+    }
+
+    // set up a few files in the test environment to simulate an active mediated device
+    pub fn populate_active_device(&self, uuid: &str, parent: &str, mdev_type: &str) {
+        use std::os::unix::fs::symlink;
+
+        let (parentdir, parenttypedir) =
+            self.populate_parent_device(parent, mdev_type, 1, "", "", None);
+
+        let parentdevdir = parentdir.join(uuid);
+        fs::create_dir_all(&parentdevdir).expect("Unable to setup parent device dir");
+
+        let devdir = self.mdev_base().join(uuid);
+        fs::create_dir_all(devdir.parent().unwrap()).expect("Unable to setup mdev dir");
+        symlink(&parentdevdir, &devdir).expect("Unable to setup mdev dir");
+
+        let typefile = devdir.join("mdev_type");
+        symlink(&parenttypedir, &typefile).expect("Unable to setup mdev type");
+    }
+
+    // set up a few files in the test environment to simulate a parent device that supports
+    // mediated devices
+    pub fn populate_parent_device(
+        &self,
+        parent: &str,
+        supported_type: &str,
+        instances: i32,
+        device_api: &str,
+        name: &str,
+        description: Option<&str>,
+    ) -> (PathBuf, PathBuf) {
+        let parentdir = self.parent_base().join(parent);
+        let names = self.sysfs_names();
+        let parenttypedir = parentdir.join(names.supported_types).join(supported_type);
+        fs::create_dir_all(&parenttypedir).expect("Unable to setup mdev parent type");
+
+        let instancefile = parenttypedir.join(names.available_instances);
+        fs::write(instancefile, format!("{}", instances))
+            .expect("Unable to write available_instances");
+
+        let apifile = parenttypedir.join("device_api");
+        fs::write(apifile, device_api).expect("Unable to write device_api");
+
+        let namefile = parenttypedir.join("name");
+        fs::write(namefile, name).expect("Unable to write name");
+
+        if let Some(desc) = description {
+            let descfile = parenttypedir.join("description");
+            fs::write(descfile, desc).expect("Unable to write description");
+        }
+
+        (parentdir, parenttypedir)
+    }
+
+    pub fn compare_to_file(&self, filename: &str, actual: &str) {
+        let path = self.datapath.join(filename);
+        let flag = get_flag(REGEN_FLAG);
+        if flag {
+            regen(&path, actual).expect("Failed to regenerate expected output");
+        }
+        let expected = fs::read_to_string(path).unwrap_or_else(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                println!(
+                    "File {:?} not found, run tests with {}=1 to automatically \
+                         generate expected output",
+                    filename, REGEN_FLAG
+                );
+            }
+            Default::default()
+        });
+
+        assert_eq!(expected, actual);
+    }
+
+    pub fn load_from_json<'a>(
+        &'a self,
+        uuid: &str,
+        parent: &str,
+        filename: &str,
+    ) -> Result<MDev<'a>> {
+        let path = self.datapath.join(filename);
+        let uuid = Uuid::parse_str(uuid);
+        assert!(uuid.is_ok());
+        let uuid = uuid.unwrap();
+        let mut dev = MDev::new(self, uuid);
+
+        let jsonstr = fs::read_to_string(path)?;
+        let jsonval: serde_json::Value = serde_json::from_str(&jsonstr)?;
+        dev.load_from_json(parent.to_string(), &jsonval)?;
+
+        Ok(dev)
+    }
+}
+
+fn get_flag(varname: &str) -> bool {
+    match env::var(varname) {
+        Err(_) => false,
+        Ok(s) => match s.trim().parse::<i32>() {
+            Err(_) => false,
+            Ok(n) => n > 0,
+        },
+    }
+}
+
+fn regen(filename: &PathBuf, data: &str) -> Result<()> {
+    let parentdir = filename.parent().unwrap();
+    fs::create_dir_all(parentdir)?;
+
+    fs::write(filename, data.as_bytes())
+        .map(|_| {
+            println!("Regenerated expected data file {:?}", filename);
+        })
+        .map_err(|err| err.into())
+}
+
+const REGEN_FLAG: &str = "MDEVCTL_TEST_REGENERATE_OUTPUT";
@@ -1,18 +1,14 @@
-use anyhow::Result;
 use log::info;
 use std::collections::BTreeMap;
-use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 use tempfile::Builder;
-use tempfile::TempDir;
 use uuid::Uuid;
 
-use crate::environment::Environment;
+use crate::environment::{Environment, PersistLayout, SysfsNames};
 use crate::logger::logger;
-use crate::mdev::MDev;
-
-const TEST_DATA_DIR: &str = "tests";
+use crate::mdev::{FormatType, MDev};
+use crate::testenv::TestEnvironment;
 
 fn init() {
     let _ = logger().is_test(true).try_init();
@@ -24,157 +20,6 @@ enum Expect {
     Fail,
 }
 
-#[derive(Debug)]
-struct TestEnvironment {
-    datapath: PathBuf,
-    scratch: TempDir,
-}
-
-impl Environment for TestEnvironment {
-    fn root(&self) -> &Path {
-        self.scratch.path()
-    }
-}
-
-impl TestEnvironment {
-    pub fn new(testname: &str, testcase: &str) -> TestEnvironment {
-        let path: PathBuf = [TEST_DATA_DIR, testname].iter().collect();
-        let scratchdir = Builder::new().prefix("mdevctl-test").tempdir().unwrap();
-        let test = TestEnvironment {
-            datapath: path,
-            scratch: scratchdir,
-        };
-        // populate the basic directories in the environment
-        fs::create_dir_all(test.mdev_base()).expect("Unable to create mdev_base");
-        fs::create_dir_all(test.persist_base()).expect("Unable to create persist_base");
-        fs::create_dir_all(test.parent_base()).expect("Unable to create parent_base");
-        info!("---- Running test '{}/{}' ----", testname, testcase);
-        test
-    }
-
-    // set up a few files in the test environment to simulate an defined mediated device
-    fn populate_defined_device(&self, uuid: &str, parent: &str, filename: &str) {
-        let jsonfile = self.datapath.join(filename);
-        let parentdir = self.persist_base().join(parent);
-        fs::create_dir_all(&parentdir).expect("Unable to setup parent dir");
-        let deffile = parentdir.join(uuid);
-        assert!(jsonfile.exists());
-        assert!(!deffile.exists());
-        fs::copy(jsonfile, deffile).expect("Unable to copy device def");
-    }
-
-    // set up a few files in the test environment to simulate an active mediated device
-    fn populate_active_device(&self, uuid: &str, parent: &str, mdev_type: &str) {
-        use std::os::unix::fs::symlink;
-
-        let (parentdir, parenttypedir) =
-            self.populate_parent_device(parent, mdev_type, 1, "", "", None);
-
-        let parentdevdir = parentdir.join(uuid);
-        fs::create_dir_all(&parentdevdir).expect("Unable to setup parent device dir");
-
-        let devdir = self.mdev_base().join(uuid);
-        fs::create_dir_all(&devdir.parent().unwrap()).expect("Unable to setup mdev dir");
-        symlink(&parentdevdir, &devdir).expect("Unable to setup mdev dir");
-
-        let typefile = devdir.join("mdev_type");
-        symlink(&parenttypedir, &typefile).expect("Unable to setup mdev type");
-    }
-
-    // set up a few files in the test environment to simulate a parent device that supports
-    // mediated devices
-    fn populate_parent_device(
-        &self,
-        parent: &str,
-        supported_type: &str,
-        instances: i32,
-        device_api: &str,
-        name: &str,
-        description: Option<&str>,
-    ) -> (PathBuf, PathBuf) {
-        let parentdir = self.parent_base().join(parent);
-        let parenttypedir = parentdir.join("mdev_supported_types").join(supported_type);
-        fs::create_dir_all(&parenttypedir).expect("Unable to setup mdev parent type");
-
-        let instancefile = parenttypedir.join("available_instances");
-        fs::write(instancefile, format!("{}", instances))
-            .expect("Unable to write available_instances");
-
-        let apifile = parenttypedir.join("device_api");
-        fs::write(apifile, format!("{}", device_api)).expect("Unable to write device_api");
-
-        let namefile = parenttypedir.join("name");
-        fs::write(namefile, format!("{}", name)).expect("Unable to write name");
-
-        if let Some(desc) = description {
-            let descfile = parenttypedir.join("description");
-            fs::write(descfile, format!("{}", desc)).expect("Unable to write description");
-        }
-
-        (parentdir, parenttypedir)
-    }
-
-    fn compare_to_file(&self, filename: &str, actual: &str) {
-        let path = self.datapath.join(filename);
-        let flag = get_flag(REGEN_FLAG);
-        if flag {
-            regen(&path, actual).expect("Failed to regenerate expected output");
-        }
-        let expected = fs::read_to_string(path).unwrap_or_else(|e| {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                println!(
-                    "File {:?} not found, run tests with {}=1 to automatically \
-                         generate expected output",
-                    filename, REGEN_FLAG
-                );
-            }
-            Default::default()
-        });
-
-        assert_eq!(expected, actual);
-    }
-
-    fn load_from_json<'a>(&'a self, uuid: &str, parent: &str, filename: &str) -> Result<MDev<'a>> {
-        let path = self.datapath.join(filename);
-        let uuid = Uuid::parse_str(uuid);
-        assert!(uuid.is_ok());
-        let uuid = uuid.unwrap();
-        let mut dev = MDev::new(self, uuid);
-
-        let jsonstr = fs::read_to_string(path)?;
-        let jsonval: serde_json::Value = serde_json::from_str(&jsonstr)?;
-        dev.load_from_json(parent.to_string(), &jsonval)?;
-
-        Ok(dev)
-    }
-}
-
-fn get_flag(varname: &str) -> bool {
-    match env::var(varname) {
-        Err(_) => {
-            return false;
-        }
-        Ok(s) => match s.trim().parse::<i32>() {
-            Err(_) => return false,
-            Ok(n) => return n > 0,
-        },
-    }
-}
-
-fn regen(filename: &PathBuf, data: &str) -> Result<()> {
-    let parentdir = filename.parent().unwrap();
-    fs::create_dir_all(parentdir)?;
-
-    fs::write(filename, data.as_bytes())
-        .and_then(|_| {
-            println!("Regenerated expected data file {:?}", filename);
-            Ok(())
-        })
-        .map_err(|err| err.into())
-}
-
-const REGEN_FLAG: &str = "MDEVCTL_TEST_REGENERATE_OUTPUT";
-
 fn test_load_json_helper(uuid: &str, parent: &str, expect: Expect) {
     let test = TestEnvironment::new("load-json", uuid);
 
@@ -186,7 +31,7 @@ fn test_load_json_helper(uuid: &str, parent: &str, expect: Expect) {
     }
 
     let dev = res.expect("Command failed unexpectedly");
-    let jsonval = dev.to_json(false).unwrap();
+    let jsonval = dev.to_json(false, false, false).unwrap();
     let jsonstr = serde_json::to_string_pretty(&jsonval).unwrap();
 
     test.compare_to_file(&format!("{}.out", uuid), &jsonstr);
@@ -238,6 +83,7 @@ fn test_load_json() {
     );
 }
 
+#[allow(clippy::too_many_arguments)]
 fn test_define_helper<F>(
     testname: &str,
     expect: Expect,
@@ -348,6 +194,28 @@ fn test_define() {
         Some(PathBuf::from("defined.json")),
         |_| {},
     );
+    // the parent may be provided by the jsonfile itself
+    test_define_helper(
+        "jsonfile-parent",
+        Expect::Pass,
+        Uuid::parse_str(DEFAULT_UUID).ok(),
+        false,
+        None,
+        None,
+        Some(PathBuf::from("defined-with-parent.json")),
+        |_| {},
+    );
+    // a parent is required if the jsonfile doesn't provide one
+    test_define_helper(
+        "jsonfile-no-parent",
+        Expect::Fail,
+        Uuid::parse_str(DEFAULT_UUID).ok(),
+        false,
+        None,
+        None,
+        Some(PathBuf::from("defined.json")),
+        |_| {},
+    );
     // If uuid is already active, specifying mdev_type will result in an error
     test_define_helper(
         "uuid-running-no-parent",
@@ -417,6 +285,110 @@ fn test_define() {
     );
 }
 
+#[test]
+fn test_json_parent() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const JSON_PARENT: &str = "0000:00:02.0";
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("define", "json-parent");
+    let uuid = Uuid::parse_str(UUID).ok();
+    let jsonfile = Some(test.datapath.join("defined-with-parent.json"));
+
+    // the parent from the json is used when none is specified explicitly
+    let dev = crate::define_command_helper(&test, uuid, false, None, None, jsonfile.clone())
+        .expect("define command failed unexpectedly");
+    assert_eq!(Some(JSON_PARENT.to_string()), dev.parent);
+    let dev = crate::start_command_helper(
+        &test,
+        uuid,
+        None,
+        None,
+        jsonfile.clone(),
+        &mut std::io::empty(),
+    )
+    .expect("start command failed unexpectedly");
+    assert_eq!(Some(JSON_PARENT.to_string()), dev.parent);
+
+    // an explicit parent overrides the one from the json
+    let dev = crate::define_command_helper(
+        &test,
+        uuid,
+        false,
+        Some(PARENT.to_string()),
+        None,
+        jsonfile.clone(),
+    )
+    .expect("define command failed unexpectedly");
+    assert_eq!(Some(PARENT.to_string()), dev.parent);
+    let dev = crate::start_command_helper(
+        &test,
+        uuid,
+        Some(PARENT.to_string()),
+        None,
+        jsonfile,
+        &mut std::io::empty(),
+    )
+    .expect("start command failed unexpectedly");
+    assert_eq!(Some(PARENT.to_string()), dev.parent);
+}
+
+#[test]
+fn test_start_json_stdin() {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:02.0";
+    const MDEV_TYPE: &str = "type-a";
+
+    let test = TestEnvironment::new("start", "json-stdin").with_parent(PARENT, MDEV_TYPE);
+    // stands in for the device directory the kernel creates, so that attributes can be written
+    let devpath = test.mdev_base().join(UUID);
+    fs::create_dir_all(&devpath).unwrap();
+    fs::write(devpath.join("foo"), "").unwrap();
+    fs::write(devpath.join("bar"), "").unwrap();
+
+    let uuid = Uuid::parse_str(UUID).ok();
+    let stdin = Some(PathBuf::from("-"));
+    let payload = serde_json::json!({
+        "mdev_type": MDEV_TYPE,
+        "start": "manual",
+        "attrs": [{"foo": "1"}, {"bar": "2"}],
+    });
+    let mut dev = crate::start_command_helper(
+        &test,
+        uuid,
+        Some(PARENT.to_string()),
+        None,
+        stdin.clone(),
+        &mut Cursor::new(payload.to_string()),
+    )
+    .expect("start command failed unexpectedly");
+    assert_eq!(Some(MDEV_TYPE.to_string()), dev.mdev_type);
+    dev.start(false, None).expect("start failed");
+    assert_eq!("1\n", fs::read_to_string(devpath.join("foo")).unwrap());
+    assert_eq!("2\n", fs::read_to_string(devpath.join("bar")).unwrap());
+
+    // the payload has to be a valid device definition
+    for invalid in ["", "[1, 2]", r#"{"start": "manual"}"#] {
+        crate::start_command_helper(
+            &test,
+            uuid,
+            Some(PARENT.to_string()),
+            None,
+            stdin.clone(),
+            &mut Cursor::new(invalid),
+        )
+        .expect_err("an invalid payload should be rejected");
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn test_modify_helper<F>(
     testname: &str,
     expect: Expect,
@@ -429,6 +401,8 @@ fn test_modify_helper<F>(
     value: Option<String>,
     auto: bool,
     manual: bool,
+    apply_now: bool,
+    dry_run: bool,
     setupfn: F,
 ) where
     F: Fn(&TestEnvironment),
@@ -444,10 +418,17 @@ fn test_modify_helper<F>(
         mdev_type,
         addattr,
         delattr,
+        None,
+        false,
         index,
         value,
         auto,
         manual,
+        apply_now,
+        dry_run,
+        None,
+        false,
+        false,
     );
     if expect == Expect::Fail {
         assert!(result.is_err());
@@ -483,6 +464,8 @@ fn test_modify() {
         None,
         false,
         false,
+        false,
+        false,
         |_| {},
     );
     test_modify_helper(
@@ -497,6 +480,8 @@ fn test_modify() {
         None,
         true,
         false,
+        false,
+        false,
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -513,6 +498,8 @@ fn test_modify() {
         None,
         false,
         true,
+        false,
+        false,
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -529,6 +516,8 @@ fn test_modify() {
         None,
         false,
         false,
+        false,
+        false,
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -545,6 +534,8 @@ fn test_modify() {
         None,
         false,
         false,
+        false,
+        false,
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -561,6 +552,8 @@ fn test_modify() {
         Some("added-attr-value".to_string()),
         false,
         false,
+        false,
+        false,
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -577,6 +570,8 @@ fn test_modify() {
         Some("added-attr-value".to_string()),
         false,
         false,
+        false,
+        false,
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -593,6 +588,8 @@ fn test_modify() {
         None,
         false,
         false,
+        false,
+        false,
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
         },
@@ -609,6 +606,8 @@ fn test_modify() {
         None,
         true,
         false,
+        false,
+        false,
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
             test.populate_defined_device(UUID, "0000:00:02.0", "defined.json");
@@ -626,6 +625,8 @@ fn test_modify() {
         None,
         true,
         false,
+        false,
+        false,
         |test| {
             test.populate_defined_device(UUID, PARENT, "defined.json");
             test.populate_defined_device(UUID, "0000:00:02.0", "defined.json");
@@ -643,6 +644,8 @@ fn test_modify() {
         None,
         true,
         true,
+        false,
+        false,
         |_| {},
     );
 }
@@ -718,6 +721,7 @@ fn test_undefine() {
     );
 }
 
+#[allow(clippy::too_many_arguments)]
 fn test_start_helper<F>(
     testname: &str,
     expect_setup: Expect,
@@ -734,7 +738,14 @@ fn test_start_helper<F>(
     setupfn(&test);
     let uuid = uuid.map(|s| Uuid::parse_str(s.as_ref()).unwrap());
 
-    let dev = crate::start_command_helper(&test, uuid, parent, mdev_type, jsonfile);
+    let dev = crate::start_command_helper(
+        &test,
+        uuid,
+        parent,
+        mdev_type,
+        jsonfile,
+        &mut std::io::empty(),
+    );
 
     if expect_setup == Expect::Fail {
         dev.expect_err("start command should have failed");
@@ -742,7 +753,7 @@ fn test_start_helper<F>(
     }
     let mut dev = dev.expect("Couldn't run start command");
 
-    let result = dev.start();
+    let result = dev.start(false, None);
     if expect_execute == Expect::Fail {
         result.expect_err("start command should have failed");
         return;
@@ -752,12 +763,12 @@ fn test_start_helper<F>(
     let create_path = test
         .parent_base()
         .join(dev.parent.unwrap())
-        .join("mdev_supported_types")
+        .join(test.sysfs_names().supported_types)
         .join(dev.mdev_type.unwrap())
-        .join("create");
+        .join(test.sysfs_names().create);
     assert!(create_path.exists());
-    if uuid.is_some() {
-        assert_eq!(uuid.unwrap(), dev.uuid);
+    if let Some(u) = uuid {
+        assert_eq!(u, dev.uuid);
     }
     let contents = fs::read_to_string(create_path).expect("Unable to read 'create' file");
     assert_eq!(dev.uuid.to_hyphenated().to_string(), contents);
@@ -983,7 +994,7 @@ fn test_stop() {
     let test = TestEnvironment::new("stop", "default");
     test.populate_active_device(UUID, PARENT, MDEV_TYPE);
 
-    crate::stop_command(&test, Uuid::parse_str(UUID).unwrap())
+    crate::stop_command(&test, Uuid::parse_str(UUID).unwrap(), false, None)
         .expect("stop command failed unexpectedly");
 
     let remove_path = test.mdev_base().join(UUID).join("remove");
@@ -992,6 +1003,47 @@ fn test_stop() {
     assert_eq!("1", contents);
 }
 
+#[test]
+fn test_retry_busy() {
+    use crate::mdev::retry_busy;
+    use std::io;
+    use std::time::Duration;
+
+    init();
+
+    let busy = || io::Error::from_raw_os_error(16);
+
+    // the first attempt fails with EBUSY and the retry succeeds
+    let mut calls = 0;
+    let res = retry_busy(3, Duration::from_millis(1), || {
+        calls += 1;
+        match calls {
+            1 => Err(busy()),
+            _ => Ok(()),
+        }
+    });
+    assert!(res.is_ok());
+    assert_eq!(2, calls);
+
+    // give up after the maximum number of attempts
+    let mut calls = 0;
+    let res = retry_busy(3, Duration::from_millis(1), || {
+        calls += 1;
+        Err(busy())
+    });
+    assert_eq!(Some(16), res.unwrap_err().raw_os_error());
+    assert_eq!(3, calls);
+
+    // other errors are not retried
+    let mut calls = 0;
+    let res = retry_busy(3, Duration::from_millis(1), || {
+        calls += 1;
+        Err(io::Error::from(io::ErrorKind::PermissionDenied))
+    });
+    assert!(res.is_err());
+    assert_eq!(1, calls);
+}
+
 #[test]
 fn test_invalid_files() {
     init();
@@ -1002,12 +1054,48 @@ fn test_invalid_files() {
     // just make sure that the list command can deal with invalid files without panic-ing
     let test = TestEnvironment::new("invalid-files", "invalid-active");
     test.populate_active_device("invalid-uuid-value", PARENT, MDEV_TYPE);
-    let result = crate::list_command(&test, false, false, false, None, None);
+    let result = crate::list_command(
+        &test,
+        false,
+        false,
+        false,
+        0,
+        false,
+        None,
+        None,
+        None,
+        None,
+        false,
+        None,
+        None,
+        false,
+        None,
+        false,
+        &mut Vec::new(),
+    );
     assert!(result.is_ok());
 
     let test = TestEnvironment::new("invalid-files", "invalid-defined");
     test.populate_defined_device("invalid-uuid-value", PARENT, "device.json");
-    let result = crate::list_command(&test, true, false, false, None, None);
+    let result = crate::list_command(
+        &test,
+        true,
+        false,
+        false,
+        0,
+        false,
+        None,
+        None,
+        None,
+        None,
+        false,
+        None,
+        None,
+        false,
+        None,
+        false,
+        &mut Vec::new(),
+    );
     assert!(result.is_ok());
 }
 
@@ -1028,22 +1116,48 @@ fn test_list_helper<F>(
 
     setupfn(&test);
 
-    let res = list_command_helper(&test, defined, false, verbose, uuid, parent.clone());
+    let res = list_command_helper(
+        &test,
+        defined,
+        false,
+        false,
+        u8::from(verbose),
+        false,
+        uuid,
+        parent.clone(),
+        None,
+        None,
+        false,
+        None,
+    );
     if expect == Expect::Fail {
         res.expect_err("expected list command to fail");
         return;
     }
 
-    let output = res.expect("list command failed unexpectedly");
+    let (output, _) = res.expect("list command failed unexpectedly");
     test.compare_to_file(&format!("{}.text", subtest), &output);
 
-    let res = list_command_helper(&test, defined, true, verbose, uuid, parent.clone());
+    let res = list_command_helper(
+        &test,
+        defined,
+        true,
+        false,
+        u8::from(verbose),
+        false,
+        uuid,
+        parent.clone(),
+        None,
+        None,
+        false,
+        None,
+    );
     if expect == Expect::Fail {
         res.expect_err("expected list command to fail");
         return;
     }
 
-    let output = res.expect("list command failed unexpectedly");
+    let (output, _) = res.expect("list command failed unexpectedly");
     test.compare_to_file(&format!("{}.json", subtest), &output);
 }
 
@@ -1246,10 +1360,19 @@ fn test_types_helper(
     expect: Expect,
     parent: Option<String>,
 ) {
+    use crate::mdev::humanize_description;
     use crate::types_command_helper;
 
     // test text output
-    let res = types_command_helper(test, parent.clone(), false);
+    let res = types_command_helper(
+        test,
+        parent.clone(),
+        false,
+        false,
+        false,
+        None,
+        humanize_description,
+    );
     if expect == Expect::Fail {
         res.expect_err("expected types command to fail");
         return;
@@ -1259,7 +1382,15 @@ fn test_types_helper(
     test.compare_to_file(&format!("{}.text", subtest), &output);
 
     // test JSON output
-    let res = types_command_helper(test, parent.clone(), true);
+    let res = types_command_helper(
+        test,
+        parent.clone(),
+        true,
+        false,
+        false,
+        None,
+        humanize_description,
+    );
     if expect == Expect::Fail {
         res.expect_err("expected types command to fail");
         return;
@@ -1328,7 +1459,3268 @@ fn test_types() {
     test_types_helper(
         &test,
         "parent-no-match",
-        Expect::Pass,
+        Expect::Fail,
         Some("missing".to_string()),
     );
 }
+
+#[test]
+fn test_types_multiline_description() {
+    use crate::mdev::humanize_description;
+    use crate::types_command_helper;
+
+    init();
+
+    const PARENT: &str = "0000:00:02.0";
+
+    let test = TestEnvironment::new("types", "multiline-description");
+    test.populate_parent_device(
+        PARENT,
+        "type-a",
+        2,
+        "vfio-pci",
+        "name",
+        Some("  low_gm_size: 128MB\nhigh_gm_size: 512MB \n\n"),
+    );
+
+    let types = crate::supported_types(&test, None).unwrap();
+    let description = "  low_gm_size: 128MB\nhigh_gm_size: 512MB";
+    assert_eq!(description, types[PARENT][0].description);
+
+    let output =
+        types_command_helper(&test, None, true, false, false, None, humanize_description).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(description, json[0][PARENT][0]["type-a"]["description"]);
+
+    let output =
+        types_command_helper(&test, None, false, false, false, None, humanize_description).unwrap();
+    assert!(
+        output.ends_with(
+            "    Description:   low_gm_size: 128MB\n                 high_gm_size: 512MB\n"
+        ),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn test_count_available() {
+    use crate::mdev::count_available;
+
+    init();
+
+    const PARENT: &str = "0000:00:02.0";
+    const NO_MDEV: &str = "0000:00:04.0";
+
+    let test = TestEnvironment::new("types", "count");
+    test.populate_parent_device(PARENT, "type-a", 7, "vfio-pci", "", None);
+    fs::create_dir_all(test.parent_base().join(NO_MDEV)).unwrap();
+
+    assert_eq!(7, count_available(&test, PARENT, "type-a").unwrap());
+
+    let e = count_available(&test, PARENT, "type-b").unwrap_err();
+    assert!(
+        e.to_string().contains("does not support mdev type"),
+        "{}",
+        e
+    );
+    for parent in &[NO_MDEV, "missing"] {
+        let e = count_available(&test, parent, "type-a").unwrap_err();
+        assert!(e.to_string().contains("not currently registered"), "{}", e);
+    }
+}
+
+#[test]
+fn test_types_single_parent() {
+    init();
+
+    const PARENT: &str = "0000:00:02.0";
+    const PARENT2: &str = "0000:00:03.0";
+    const NO_MDEV: &str = "0000:00:04.0";
+
+    let test = TestEnvironment::new("types", "single-parent");
+    test.populate_parent_device(PARENT, "type-a", 2, "vfio-pci", "", None);
+    test.populate_parent_device(PARENT2, "type-b", 1, "vfio-pci", "", None);
+    fs::create_dir_all(test.parent_base().join(NO_MDEV)).unwrap();
+    // a broken type of another parent doesn't matter when only one parent is looked at
+    fs::remove_file(
+        test.parent_base()
+            .join(PARENT2)
+            .join(test.sysfs_names().supported_types)
+            .join("type-b")
+            .join(test.sysfs_names().available_instances),
+    )
+    .unwrap();
+
+    let types = crate::supported_types(&test, Some(PARENT.to_string())).unwrap();
+    assert_eq!(vec![PARENT], types.keys().collect::<Vec<_>>());
+    assert_eq!("type-a", types[PARENT][0].typename);
+    crate::supported_types(&test, None).expect_err("the broken type should fail a full scan");
+
+    let e = crate::supported_types(&test, Some(NO_MDEV.to_string())).unwrap_err();
+    assert!(
+        e.to_string().contains("does not support mediated devices"),
+        "{}",
+        e
+    );
+    let e = crate::supported_types(&test, Some("missing".to_string())).unwrap_err();
+    assert!(e.to_string().contains(PARENT), "{}", e);
+}
+
+#[test]
+fn test_read_only() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let mut test = TestEnvironment::new("read-only", "default");
+    test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+    test.read_only = true;
+
+    let uuid = Uuid::parse_str(UUID).unwrap();
+    let mut dev = MDev::new(&test, uuid);
+
+    // read paths continue to work
+    dev.load_from_sysfs()
+        .expect("load_from_sysfs failed in read-only mode");
+    dev.load_definition()
+        .expect("load_definition failed in read-only mode");
+    assert!(dev.active);
+    assert!(dev.is_defined());
+    dev.to_text(FormatType::Active, 1, false)
+        .expect("to_text failed in read-only mode");
+
+    // every mutating operation must fail without touching the filesystem
+    let persist_path = dev.persist_path().unwrap();
+    let before = fs::read_to_string(&persist_path).unwrap();
+    dev.create(false)
+        .expect_err("create should fail in read-only mode");
+    dev.stop(false, None)
+        .expect_err("stop should fail in read-only mode");
+    assert!(!test.mdev_base().join(UUID).join("remove").exists());
+    dev.write_attr("mdev_type", "value")
+        .expect_err("write_attr should fail in read-only mode");
+    dev.write_config()
+        .expect_err("write_config should fail in read-only mode");
+    dev.undefine()
+        .expect_err("undefine should fail in read-only mode");
+    assert_eq!(before, fs::read_to_string(&persist_path).unwrap());
+    assert!(dev.active);
+}
+
+#[test]
+fn test_iter_defined_devices() {
+    init();
+
+    const UUID: &[&str] = &[
+        "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+        "4a0a190f-dcf3-4def-9342-c48768f0c940",
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+    ];
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("iter-defined", "default");
+    test.populate_defined_device(UUID[0], PARENT, "device.json");
+    test.populate_defined_device(UUID[1], PARENT, "device.json");
+    test.populate_defined_device(UUID[2], PARENT, "malformed.json");
+
+    // devices are loaded lazily in uuid order, so finding an early device must not parse the
+    // malformed definition at the end
+    let target = Uuid::parse_str(UUID[1]).unwrap();
+    let mut parsed = 0;
+    let dev = crate::iter_defined_devices(&test, None, None)
+        .expect("failed to iterate defined devices")
+        .inspect(|_| parsed += 1)
+        .find(|d| d.as_ref().is_ok_and(|d| d.uuid == target))
+        .expect("device not found")
+        .expect("failed to load device");
+    assert_eq!(target, dev.uuid);
+    assert_eq!(2, parsed);
+
+    // exhausting the iterator reaches the malformed definition
+    let results: Vec<_> = crate::iter_defined_devices(&test, None, None)
+        .unwrap()
+        .collect();
+    assert_eq!(3, results.len());
+    assert!(results[2].is_err());
+}
+
+#[test]
+fn test_iter_defined_devices_removed() {
+    init();
+
+    const UUID: &[&str] = &[
+        "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+        "4a0a190f-dcf3-4def-9342-c48768f0c940",
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+    ];
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("iter-defined", "removed");
+    for uuid in UUID {
+        test.populate_defined_device(uuid, PARENT, "device.json");
+    }
+
+    // the directory has been walked when another process undefines a device
+    let devices = crate::iter_defined_devices(&test, None, None).unwrap();
+    fs::remove_file(test.persist_base().join(PARENT).join(UUID[1])).unwrap();
+    let uuids: Vec<String> = devices
+        .map(|d| d.expect("failed to load device").uuid.to_string())
+        .collect();
+    assert_eq!(vec![UUID[0], UUID[2]], uuids);
+}
+
+#[test]
+fn test_export() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const PARENT2: &str = "0000:00:02.0";
+
+    let test = TestEnvironment::new("export", "default");
+    let uuid = Uuid::parse_str(UUID).unwrap();
+
+    // exporting an undefined device is an error
+    crate::export_command_helper(&test, uuid, None, false, false)
+        .expect_err("export of an undefined device should fail");
+
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+    let output = crate::export_command_helper(&test, uuid, None, false, false)
+        .expect("export command failed unexpectedly");
+    let ondisk = fs::read_to_string(test.persist_base().join(PARENT).join(UUID)).unwrap();
+    assert_eq!(ondisk.trim_end(), output);
+
+    // ambiguous without a parent once the uuid is defined on multiple parents
+    test.populate_defined_device(UUID, PARENT2, "defined.json");
+    crate::export_command_helper(&test, uuid, None, false, false)
+        .expect_err("export of an ambiguous device should fail");
+    let output = crate::export_command_helper(&test, uuid, Some(PARENT.to_string()), false, false)
+        .expect("export command failed unexpectedly");
+    assert_eq!(ondisk.trim_end(), output);
+}
+
+#[test]
+fn test_to_json_flat_attrs() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("export", "flat-attrs");
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some("type-a".to_string());
+    dev.attrs = vec![
+        ("b".to_string(), "1".to_string()),
+        ("a".to_string(), "2".to_string()),
+    ];
+
+    assert_eq!(
+        serde_json::json!({"mdev_type": "type-a", "start": "manual",
+            "attrs": [{"b": "1"}, {"a": "2"}]}),
+        dev.to_json(false, false, false).unwrap()
+    );
+    let flat = dev.to_json(true, true, false).unwrap();
+    assert_eq!(
+        serde_json::json!({UUID: {"mdev_type": "type-a", "start": "manual",
+            "attrs": {"b": "1", "a": "2"}}}),
+        flat
+    );
+    // the order of the attributes is kept
+    assert_eq!(
+        vec!["b", "a"],
+        flat[UUID]["attrs"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>()
+    );
+
+    dev.attrs.push(("b".to_string(), "3".to_string()));
+    dev.to_json(false, false, false)
+        .expect("duplicate attributes should be kept in an array");
+    let e = dev
+        .to_json(false, true, false)
+        .expect_err("duplicate attributes can't be flattened");
+    assert!(e.to_string().contains("'b'"));
+}
+
+#[test]
+fn test_device_config_round_trip() {
+    use crate::mdev::DeviceConfig;
+    use std::convert::TryFrom;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("export", "config-round-trip");
+    let contents = fs::read_to_string(test.datapath.join("defined.json")).unwrap();
+    let val: serde_json::Value = serde_json::from_str(&contents).unwrap();
+
+    let config = DeviceConfig::from_json(&val).unwrap();
+    assert_eq!("vfio_ap-passthrough", config.mdev_type);
+    assert!(!config.autostart);
+    assert_eq!(
+        ("assign_adapter".to_string(), "6".to_string()),
+        config.attrs[1]
+    );
+    let output = serde_json::to_string_pretty(&config.to_json()).unwrap();
+    assert_eq!(contents.trim_end(), output);
+
+    // a device loaded from the definition gives the same definition back
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.load_from_json(PARENT.to_string(), &val).unwrap();
+    assert_eq!(config, DeviceConfig::try_from(&dev).unwrap());
+    let output = serde_json::to_string_pretty(&dev.to_json(false, false, false).unwrap()).unwrap();
+    assert_eq!(contents.trim_end(), output);
+
+    // and a device built from the definition is the same device
+    let uuid = Uuid::parse_str(UUID).unwrap();
+    let built = MDev::try_from((
+        &test as &dyn Environment,
+        uuid,
+        PARENT.to_string(),
+        config.clone(),
+    ))
+    .unwrap();
+    assert_eq!(Some(PARENT), built.parent.as_deref());
+    assert_eq!(config, DeviceConfig::try_from(&built).unwrap());
+    let output =
+        serde_json::to_string_pretty(&built.to_json(false, false, false).unwrap()).unwrap();
+    assert_eq!(contents.trim_end(), output);
+
+    // names that can't refer to a single sysfs entry are rejected
+    let build = |parent: &str, mdev_type: &str, attr: &str| {
+        let mut config = config.clone();
+        config.mdev_type = mdev_type.to_string();
+        config.attrs.push((attr.to_string(), "1".to_string()));
+        MDev::try_from((&test as &dyn Environment, uuid, parent.to_string(), config))
+    };
+    build(PARENT, "vfio_ap-passthrough", "foo").expect("valid names should be accepted");
+    for (parent, mdev_type, attr) in &[
+        ("", "vfio_ap-passthrough", "foo"),
+        ("../0000:00:03.0", "vfio_ap-passthrough", "foo"),
+        (PARENT, "", "foo"),
+        (PARENT, "..", "foo"),
+        (PARENT, "vfio_ap-passthrough", "a/b"),
+        (PARENT, "vfio_ap-passthrough", "."),
+    ] {
+        build(parent, mdev_type, attr).expect_err("invalid name should be rejected");
+    }
+
+    // malformed definitions are rejected rather than partially loaded
+    for invalid in &[
+        serde_json::json!({"start": "manual"}),
+        serde_json::json!({"mdev_type": 1, "start": "manual"}),
+        serde_json::json!({"mdev_type": "t", "start": "auto", "attrs": [{"a": 1}]}),
+        serde_json::json!({"mdev_type": "t", "start": "auto", "attrs": [{"a": "1", "b": "2"}]}),
+    ] {
+        DeviceConfig::from_json(invalid).expect_err("invalid definition should fail");
+    }
+}
+
+#[test]
+fn test_to_json_sorted() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("export", "sorted");
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some("type-a".to_string());
+    dev.attrs = vec![
+        ("c".to_string(), "1".to_string()),
+        ("a".to_string(), "2".to_string()),
+        ("b".to_string(), "3".to_string()),
+        ("a".to_string(), "4".to_string()),
+    ];
+
+    let sorted = dev.to_json_sorted(false, false).unwrap();
+    assert_eq!(
+        serde_json::json!({"mdev_type": "type-a", "start": "manual",
+            "attrs": [{"a": "2"}, {"a": "4"}, {"b": "3"}, {"c": "1"}]}),
+        sorted
+    );
+    assert_ne!(dev.to_json(false, false, false).unwrap(), sorted);
+
+    // the device itself keeps the order in which attributes are written
+    assert_eq!("c", dev.attrs[0].0);
+    dev.attrs.pop();
+    let flat = dev.to_json_sorted(true, true).unwrap();
+    assert_eq!(
+        vec!["a", "b", "c"],
+        flat[UUID]["attrs"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_to_json_state() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "type-a";
+
+    let test = TestEnvironment::new("list", "json-state").with_definition(
+        UUID,
+        PARENT,
+        &serde_json::json!({"mdev_type": MDEV_TYPE, "start": "manual", "attrs": []}),
+    );
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.load_definition().unwrap();
+
+    // the state is left out by default
+    let json = dev.to_json(false, false, false).unwrap();
+    assert!(json.get("active").is_none());
+    assert!(json.get("defined").is_none());
+
+    let json = dev.to_json(false, false, true).unwrap();
+    assert_eq!(serde_json::json!(false), json["active"]);
+    assert_eq!(serde_json::json!(true), json["defined"]);
+
+    dev.active = true;
+    dev.undefine().unwrap();
+    let json = dev.to_json(true, false, true).unwrap();
+    assert_eq!(serde_json::json!(true), json[UUID]["active"]);
+    assert_eq!(serde_json::json!(false), json[UUID]["defined"]);
+}
+
+#[test]
+fn test_modify_apply_now() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "vfio_ap-passthrough";
+
+    let uuid = Uuid::parse_str(UUID).unwrap();
+
+    // an inactive device only has its definition updated
+    let test = TestEnvironment::new("modify", "apply-now-inactive");
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+    crate::modify_command(
+        &test,
+        uuid,
+        Some(PARENT.to_string()),
+        None,
+        Some("added-attr".to_string()),
+        false,
+        None,
+        false,
+        None,
+        Some("added-attr-value".to_string()),
+        false,
+        false,
+        true,
+        false,
+        None,
+        false,
+        false,
+    )
+    .expect("modify command failed unexpectedly");
+    let def = crate::get_defined_device(&test, uuid, Some(&PARENT.to_string())).unwrap();
+    assert!(!def.active);
+    assert_eq!(
+        Some(&("added-attr".to_string(), "added-attr-value".to_string())),
+        def.attrs.last()
+    );
+
+    // an active device has the definition updated and the added attribute written to sysfs
+    let mut test = TestEnvironment::new("modify", "apply-now-active");
+    test.interpolate_attrs = true;
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+    test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+    let attrpath = test.mdev_base().join(UUID).join("added-attr");
+    fs::write(&attrpath, "").unwrap();
+    crate::modify_command(
+        &test,
+        uuid,
+        Some(PARENT.to_string()),
+        None,
+        Some("added-attr".to_string()),
+        false,
+        None,
+        false,
+        None,
+        Some("added-attr-value".to_string()),
+        false,
+        false,
+        true,
+        false,
+        None,
+        false,
+        false,
+    )
+    .expect("modify command failed unexpectedly");
+    let def = crate::get_defined_device(&test, uuid, Some(&PARENT.to_string())).unwrap();
+    assert!(def.active);
+    assert_eq!(
+        Some(&("added-attr".to_string(), "added-attr-value".to_string())),
+        def.attrs.last()
+    );
+    assert_eq!("added-attr-value\n", fs::read_to_string(&attrpath).unwrap());
+
+    // removing an attribute or changing the type can't be applied to the running device
+    let mut modified = def.clone();
+    modified.mdev_type = Some("different_type".to_string());
+    modified.delete_attribute(Some(0)).unwrap();
+    let restart = modified.apply_live(&def);
+    assert_eq!(2, restart.len());
+
+    // an added attribute is written with its references resolved, as when starting the device
+    let mut modified = def.clone();
+    modified
+        .add_attribute("added-attr".to_string(), "id-${uuid}".to_string(), None)
+        .unwrap();
+    assert!(modified.apply_live(&def).is_empty());
+    assert_eq!(
+        format!("id-{}\n", UUID),
+        fs::read_to_string(&attrpath).unwrap()
+    );
+}
+
+#[test]
+fn test_modify_new_uuid() {
+    init();
+
+    const UUID1: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const UUID3: &str = "b6f7e33f-ea28-4f9d-8c42-797ff0ec2888";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "vfio_ap-passthrough";
+
+    let def = serde_json::json!({"mdev_type": MDEV_TYPE, "start": "manual",
+        "attrs": [{"assign_adapter": "5"}]});
+    let test = TestEnvironment::new("modify", "new-uuid")
+        .with_definition(UUID1, PARENT, &def)
+        .with_definition(UUID2, PARENT, &def);
+    let rename = |old: &str, new: &str| {
+        crate::modify_command(
+            &test,
+            Uuid::parse_str(old).unwrap(),
+            None,
+            None,
+            None,
+            false,
+            None,
+            false,
+            None,
+            None,
+            false,
+            false,
+            false,
+            false,
+            Some(Uuid::parse_str(new).unwrap()),
+            false,
+            false,
+        )
+    };
+    let defpath = |uuid: &str| test.persist_base().join(PARENT).join(uuid);
+
+    rename(UUID1, UUID3).expect("renaming a defined device should succeed");
+    assert!(!defpath(UUID1).exists());
+    let dev = crate::get_defined_device(&test, Uuid::parse_str(UUID3).unwrap(), None).unwrap();
+    assert_eq!(def, dev.to_json(false, false, false).unwrap());
+
+    // the new uuid is already defined
+    rename(UUID3, UUID2).expect_err("renaming to a defined uuid should fail");
+    assert!(defpath(UUID2).exists());
+    assert!(defpath(UUID3).exists());
+
+    // the device is running
+    test.populate_active_device(UUID2, PARENT, MDEV_TYPE);
+    rename(UUID2, UUID1).expect_err("renaming an active device should fail");
+    assert!(defpath(UUID2).exists());
+    assert!(!defpath(UUID1).exists());
+}
+
+#[test]
+fn test_migrate() {
+    init();
+
+    const UUID1: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const UUID3: &str = "b6f7e33f-ea28-4f9d-8c42-797ff0ec2888";
+    const PARENT: &str = "0000:00:03.0";
+
+    let def = serde_json::json!({"mdev_type": "type-a", "start": "auto",
+        "attrs": [{"foo": "1"}]});
+    let test = TestEnvironment::new("migrate", "default").with_definition(UUID3, PARENT, &def);
+    // a flat file directly in the configuration directory naming its parent
+    let flat = test.persist_base().join(UUID1);
+    let mut flatdef = def.clone();
+    flatdef["parent"] = PARENT.into();
+    fs::write(&flat, flatdef.to_string()).unwrap();
+    // a definition wrapped in an object keyed by its uuid
+    let wrapped = test.persist_base().join(PARENT).join(UUID2);
+    fs::write(&wrapped, serde_json::json!({ UUID2: def }).to_string()).unwrap();
+
+    // legacy definitions are detected on read
+    let uuid2 = Uuid::parse_str(UUID2).unwrap();
+    let dev = crate::get_defined_device(&test, uuid2, None).unwrap();
+    assert_eq!(def, dev.to_json(false, false, false).unwrap());
+    let found = crate::list_devices(&test, true, None, None, None, None, false, None)
+        .unwrap()
+        .0;
+    assert_eq!(2, found[PARENT].len());
+
+    let legacy = crate::legacy_definitions(&test).unwrap();
+    let paths: Vec<_> = legacy.iter().map(|(p, _)| p.clone()).collect();
+    assert_eq!(vec![wrapped.clone(), flat.clone()], paths);
+
+    crate::migrate_command(&test, true).unwrap();
+    assert!(flat.exists());
+
+    crate::migrate_command(&test, false).unwrap();
+    assert!(!flat.exists());
+    for uuid in [UUID1, UUID2, UUID3] {
+        let dev = crate::get_defined_device(&test, Uuid::parse_str(uuid).unwrap(), None).unwrap();
+        assert_eq!(def, dev.to_json(false, false, false).unwrap());
+        let ondisk: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(test.persist_base().join(PARENT).join(uuid)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(def, ondisk);
+    }
+    assert!(crate::legacy_definitions(&test).unwrap().is_empty());
+}
+
+#[test]
+fn test_config_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+
+    let test = TestEnvironment::new("config-permissions", "default");
+    let dev = test.load_from_json(UUID, PARENT, "defined.json").unwrap();
+    dev.write_config().expect("Failed to write config");
+    let path = dev.persist_path().unwrap();
+    assert_eq!(0o644, mode(&path));
+    assert_eq!(0o755, mode(path.parent().unwrap()));
+
+    // permissions are applied regardless of the permissions of an existing file
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+    dev.write_config().expect("Failed to write config");
+    assert_eq!(0o644, mode(&path));
+
+    // permissions are configurable by the environment
+    let mut test = TestEnvironment::new("config-permissions", "custom");
+    test.config_modes = (0o700, 0o600);
+    let dev = test.load_from_json(UUID, PARENT, "defined.json").unwrap();
+    dev.write_config().expect("Failed to write config");
+    let path = dev.persist_path().unwrap();
+    assert_eq!(0o600, mode(&path));
+    assert_eq!(0o700, mode(path.parent().unwrap()));
+}
+
+#[test]
+fn test_sysfs_autostart() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "vfio_ap-passthrough";
+
+    let uuid = Uuid::parse_str(UUID).unwrap();
+    let test = TestEnvironment::new("sysfs-autostart", "default");
+    test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+
+    // without a hint, the device defaults to manual start
+    let mut dev = MDev::new(&test, uuid);
+    dev.load_from_sysfs().unwrap();
+    assert!(dev.active);
+    assert!(!dev.autostart);
+
+    // an undefined device picks up the hint from sysfs
+    let hint = test.mdev_base().join(UUID).join("autostart");
+    fs::write(&hint, "1\n").unwrap();
+    let mut dev = MDev::new(&test, uuid);
+    dev.load_from_sysfs().unwrap();
+    assert!(dev.autostart);
+
+    // an unrecognized value is ignored
+    fs::write(&hint, "bogus\n").unwrap();
+    let mut dev = MDev::new(&test, uuid);
+    dev.load_from_sysfs().unwrap();
+    assert!(!dev.autostart);
+
+    // the stored definition takes precedence over the hint
+    fs::write(&hint, "1\n").unwrap();
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+    let dev = crate::get_defined_device(&test, uuid, None).unwrap();
+    assert!(dev.active);
+    assert!(!dev.autostart);
+}
+
+#[test]
+fn test_modify_dry_run() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("modify", "dry-run");
+    test.populate_defined_device(UUID, PARENT, "defined.json");
+    let uuid = Uuid::parse_str(UUID).unwrap();
+
+    let orig = crate::get_defined_device(&test, uuid, None).unwrap();
+    let mut dev = orig.clone();
+    dev.add_attribute(
+        "added-attr".to_string(),
+        "added-attr-value".to_string(),
+        Some(3),
+    )
+    .unwrap();
+    let diff = crate::definition_diff(&orig, &dev).expect("Failed to generate diff");
+    let root = format!("{}", test.root().display());
+    test.compare_to_file("dry-run.diff", &diff.replace(&root, ""));
+
+    // a dry run doesn't modify the definition
+    let path = orig.persist_path().unwrap();
+    let before = fs::read_to_string(&path).unwrap();
+    crate::modify_command(
+        &test,
+        uuid,
+        None,
+        None,
+        Some("added-attr".to_string()),
+        false,
+        None,
+        false,
+        Some(3),
+        Some("added-attr-value".to_string()),
+        false,
+        false,
+        false,
+        true,
+        None,
+        false,
+        false,
+    )
+    .expect("modify command failed unexpectedly");
+    assert_eq!(before, fs::read_to_string(&path).unwrap());
+}
+
+#[test]
+fn test_load_definition_errors() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("load-definition", "default");
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    let path = format!("{:?}", dev.persist_path().unwrap());
+
+    // missing file
+    let err = dev.load_definition().expect_err("load should fail");
+    assert!(format!("{:#}", err).contains(&path));
+
+    // malformed file
+    test.populate_defined_device(UUID, PARENT, "malformed.json");
+    let err = dev.load_definition().expect_err("load should fail");
+    assert!(format!("{:#}", err).contains(&path));
+}
+
+#[test]
+fn test_list_undefined_only() {
+    init();
+
+    const UUID: &[&str] = &[
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5a492",
+    ];
+    const PARENT: &str = "0000:00:02.0";
+    const MDEV_TYPE: &str = "arbitrary_type1";
+
+    let test = TestEnvironment::new("list", "undefined-only");
+    test.populate_active_device(UUID[0], PARENT, MDEV_TYPE);
+    test.populate_defined_device(UUID[0], PARENT, "device2.json");
+    test.populate_active_device(UUID[1], PARENT, MDEV_TYPE);
+
+    let (output, _) = crate::list_command_helper(
+        &test, false, false, false, 0, false, None, None, None, None, false, None,
+    )
+    .unwrap();
+    assert_eq!(2, output.lines().count());
+
+    let (output, _) = crate::list_command_helper(
+        &test, false, false, false, 0, false, None, None, None, None, true, None,
+    )
+    .unwrap();
+    assert_eq!(
+        format!("{} {} {} manual\n", UUID[1], PARENT, MDEV_TYPE),
+        output
+    );
+}
+
+#[test]
+fn test_types_available_only() {
+    use crate::mdev::humanize_description;
+
+    init();
+
+    let test = TestEnvironment::new("types", "available-only");
+    test.populate_parent_device("0000:00:02.0", "type-a", 2, "vfio-pci", "A", None);
+    test.populate_parent_device("0000:00:02.0", "type-b", 0, "vfio-pci", "B", None);
+    test.populate_parent_device("0000:00:03.0", "type-c", 0, "vfio-pci", "C", None);
+
+    let output =
+        crate::types_command_helper(&test, None, false, false, false, None, humanize_description)
+            .unwrap();
+    for t in ["type-a", "type-b", "type-c"] {
+        assert!(output.contains(t));
+    }
+
+    let output =
+        crate::types_command_helper(&test, None, false, false, true, None, humanize_description)
+            .unwrap();
+    assert!(output.contains("type-a"));
+    assert!(!output.contains("type-b"));
+    // parents without any available types are omitted as well
+    assert!(!output.contains("0000:00:03.0"));
+
+    let output =
+        crate::types_command_helper(&test, None, true, false, true, None, humanize_description)
+            .unwrap();
+    let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(1, json.as_array().unwrap().len());
+}
+
+#[test]
+fn test_types_parent_summary() {
+    use crate::mdev::humanize_description;
+
+    init();
+
+    const PARENT1: &str = "0000:00:02.0";
+    const PARENT2: &str = "0.0.26ab";
+
+    let test = TestEnvironment::new("types", "parent-summary");
+    test.populate_parent_device(PARENT1, "type-a", 2, "vfio-pci", "", None);
+    test.populate_parent_device(PARENT1, "type-b", 1, "vfio-pci", "", None);
+    test.populate_active_device(
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        PARENT2,
+        "vfio_ccw-io",
+    );
+    // overrides the type details set up along with the active device
+    test.populate_parent_device(PARENT2, "vfio_ccw-io", 0, "vfio-ccw", "", None);
+
+    let output =
+        crate::types_command_helper(&test, None, true, true, false, None, humanize_description)
+            .unwrap();
+    let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(
+        serde_json::json!({
+            "mdev_supported": true,
+            "device_apis": ["vfio-pci"],
+            "active_instances": 0,
+            "max_available_instances": 2,
+        }),
+        json[0][PARENT1]["summary"]
+    );
+    assert_eq!(2, json[0][PARENT1]["types"].as_array().unwrap().len());
+    assert_eq!(
+        serde_json::json!({
+            "mdev_supported": true,
+            "device_apis": ["vfio-ccw"],
+            "active_instances": 1,
+            "max_available_instances": 0,
+        }),
+        json[0][PARENT2]["summary"]
+    );
+    assert_eq!(
+        serde_json::json!([{"vfio_ccw-io": {"available_instances": 0, "device_api": "vfio-ccw"}}]),
+        json[0][PARENT2]["types"]
+    );
+
+    // filtering the listed types leaves the summary of a parent unchanged
+    test.populate_parent_device(PARENT1, "type-c", 0, "vfio-ap", "", None);
+    let output =
+        crate::types_command_helper(&test, None, true, true, false, None, humanize_description)
+            .unwrap();
+    let all: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(3, all[0][PARENT1]["types"].as_array().unwrap().len());
+    let output =
+        crate::types_command_helper(&test, None, true, true, true, None, humanize_description)
+            .unwrap();
+    let available: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(2, available[0][PARENT1]["types"].as_array().unwrap().len());
+    assert!(available[0].get(PARENT2).is_none());
+    assert_eq!(
+        serde_json::json!({
+            "mdev_supported": true,
+            "device_apis": ["vfio-ap", "vfio-pci"],
+            "active_instances": 0,
+            "max_available_instances": 2,
+        }),
+        available[0][PARENT1]["summary"]
+    );
+    assert_eq!(all[0][PARENT1]["summary"], available[0][PARENT1]["summary"]);
+}
+
+#[test]
+fn test_write_types_json() {
+    use crate::mdev::{write_types_json, MDevType};
+
+    init();
+
+    let mktype = |parent: &str, typename: &str, instances: i32, name: &str, desc: &str| {
+        let mut t = MDevType::new();
+        t.parent = parent.to_string();
+        t.typename = typename.to_string();
+        t.available_instances = instances;
+        t.device_api = "vfio-pci".to_string();
+        t.name = name.to_string();
+        t.description = desc.to_string();
+        t
+    };
+    let types = vec![
+        mktype("0000:00:03.0", "type-b", 2, "", ""),
+        mktype("0000:00:02.0", "type-c", 1, "name c", ""),
+        mktype("0000:00:03.0", "type-a", 4, "name a", "description a"),
+        mktype("0000:00:02.0", "type-a", 0, "", "description a"),
+    ];
+
+    let mut output = Vec::new();
+    write_types_json(&types, &mut output, false).expect("Failed to write types json");
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(
+        concat!(
+            r#"[{"0000:00:02.0":["#,
+            r#"{"type-a":{"available_instances":0,"device_api":"vfio-pci","description":"description a"}},"#,
+            r#"{"type-c":{"available_instances":1,"device_api":"vfio-pci","name":"name c"}}],"#,
+            r#""0000:00:03.0":["#,
+            r#"{"type-a":{"available_instances":4,"device_api":"vfio-pci","name":"name a","description":"description a"}},"#,
+            r#"{"type-b":{"available_instances":2,"device_api":"vfio-pci"}}]}]"#
+        ),
+        output
+    );
+
+    // the pretty form is the same document
+    let mut pretty = Vec::new();
+    write_types_json(&types, &mut pretty, true).expect("Failed to write types json");
+    let pretty: serde_json::Value = serde_json::from_slice(&pretty).unwrap();
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&output).unwrap(),
+        pretty
+    );
+
+    // an empty collection is an empty array
+    let mut output = Vec::new();
+    write_types_json(&[], &mut output, false).expect("Failed to write types json");
+    assert_eq!("[]", String::from_utf8(output).unwrap());
+}
+
+#[test]
+fn test_type_set_difference() {
+    use crate::mdev::{read_types_json, type_set_difference, write_types_json, MDevType};
+
+    init();
+
+    let mktype = |parent: &str, typename: &str, instances: i32, name: &str| {
+        let mut t = MDevType::new();
+        t.parent = parent.to_string();
+        t.typename = typename.to_string();
+        t.available_instances = instances;
+        t.device_api = "vfio-pci".to_string();
+        t.name = name.to_string();
+        t
+    };
+    let ours = vec![
+        mktype("0000:00:02.0", "type-a", 4, "name a"),
+        mktype("0000:00:02.0", "type-b", 2, ""),
+        mktype("0000:00:03.0", "type-c", 1, "name c"),
+    ];
+    // the parent and available instances don't matter, but the other fields do
+    let theirs = vec![
+        mktype("0000:3b:00.0", "type-a", 0, "name a"),
+        mktype("0000:3b:00.0", "type-c", 1, "another name"),
+        mktype("0000:3b:00.0", "type-d", 1, ""),
+    ];
+
+    let missing = type_set_difference(&ours, &theirs);
+    let names: Vec<&str> = missing.iter().map(|t| t.typename.as_str()).collect();
+    assert_eq!(vec!["type-b", "type-c"], names);
+    assert_eq!("0000:00:02.0", missing[0].parent);
+    assert!(type_set_difference(&ours, &ours).is_empty());
+    assert_eq!(ours, type_set_difference(&ours, &[]));
+
+    // types can be read back in from their json representation
+    let mut output = Vec::new();
+    write_types_json(&theirs, &mut output, false).unwrap();
+    let read = read_types_json(&serde_json::from_slice(&output).unwrap()).unwrap();
+    assert_eq!(theirs, read);
+    assert_eq!("0000:3b:00.0", read[0].parent);
+    read_types_json(&serde_json::json!({"type-a": {}})).expect_err("invalid json should fail");
+}
+
+#[test]
+fn test_humanize_description() {
+    use crate::mdev::{humanize_byte_sizes, humanize_description};
+
+    init();
+
+    const RAW: &str =
+        "num_heads=4, framebuffer=1073741824, reserved=1572864, max_resolution=4096x2160";
+    assert_eq!(RAW, humanize_description(RAW));
+    assert_eq!(
+        "num_heads=4, framebuffer=1 GiB, reserved=1.5 MiB, max_resolution=4096x2160",
+        humanize_byte_sizes(RAW)
+    );
+    // small numbers and non-numeric values are left alone
+    assert_eq!(
+        "frl_config=60, name=GRID",
+        humanize_byte_sizes("frl_config=60, name=GRID")
+    );
+}
+
+#[test]
+fn test_adopt() {
+    use std::os::unix::fs::PermissionsExt;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "arbitrary_type";
+
+    let test = TestEnvironment::new("adopt", "default");
+    let uuid = Uuid::parse_str(UUID).unwrap();
+
+    // can't adopt a device that isn't active
+    crate::adopt_command_helper(&test, uuid, true, false)
+        .expect_err("adopting an inactive device should fail");
+
+    test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+    let devdir = test.mdev_base().join(UUID);
+    fs::write(devdir.join("attr2"), "value2\n").unwrap();
+    fs::write(devdir.join("attr1"), "value1\n").unwrap();
+    fs::write(devdir.join("uevent"), "MDEV_TYPE=arbitrary_type\n").unwrap();
+    fs::write(devdir.join("readonly"), "ro\n").unwrap();
+    fs::set_permissions(devdir.join("readonly"), fs::Permissions::from_mode(0o444)).unwrap();
+    fs::create_dir(devdir.join("power")).unwrap();
+
+    let dev = crate::adopt_command_helper(&test, uuid, true, false)
+        .expect("adopt command failed unexpectedly");
+    dev.define().expect("Failed to define device");
+    let filecontents = fs::read_to_string(dev.persist_path().unwrap()).unwrap();
+    test.compare_to_file("adopted.expected", &filecontents);
+
+    // refuse to replace the definition unless forced
+    crate::adopt_command_helper(&test, uuid, false, false)
+        .expect_err("adopting a defined device should fail");
+    let dev = crate::adopt_command_helper(&test, uuid, false, true)
+        .expect("forced adopt command failed unexpectedly");
+    assert!(!dev.autostart);
+}
+
+#[test]
+fn test_write_config_to() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("write-config-to", "default");
+    let dev = test.load_from_json(UUID, PARENT, "defined.json").unwrap();
+
+    let outdir = Builder::new().prefix("mdevctl-staging").tempdir().unwrap();
+    let path = outdir.path().join("staged").join("device.json");
+    dev.write_config_to(&path)
+        .expect("Failed to write config to custom path");
+    assert!(!dev.is_defined());
+
+    let expected =
+        serde_json::to_string_pretty(&dev.to_json(false, false, false).unwrap()).unwrap();
+    assert_eq!(expected, fs::read_to_string(&path).unwrap());
+    // no temporary files are left behind
+    assert_eq!(1, path.parent().unwrap().read_dir().unwrap().count());
+
+    // the content is identical to the canonical definition
+    dev.write_config().expect("Failed to write config");
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        fs::read_to_string(dev.persist_path().unwrap()).unwrap()
+    );
+}
+
+#[test]
+fn test_environment_builder() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("environment", "builder")
+        .with_parent(PARENT, MDEV_TYPE)
+        .with_active_device(UUID, PARENT, MDEV_TYPE)
+        .with_definition(
+            UUID2,
+            PARENT,
+            &serde_json::json!({"mdev_type": MDEV_TYPE, "start": "manual"}),
+        );
+
+    let types = crate::supported_types(&test, Some(PARENT.to_string())).unwrap();
+    assert_eq!(1, types.len());
+    assert_eq!(MDEV_TYPE, types[PARENT][0].typename);
+
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.load_from_sysfs().unwrap();
+    assert!(dev.active);
+    assert_eq!(Some(PARENT), dev.parent.as_deref());
+    assert_eq!(Some(MDEV_TYPE), dev.mdev_type.as_deref());
+    assert!(!dev.is_defined());
+
+    let defined = crate::defined_devices(&test, None, None).unwrap();
+    assert_eq!(1, defined[PARENT].len());
+    let dev = &defined[PARENT][0];
+    assert_eq!(UUID2, dev.uuid.to_hyphenated().to_string());
+    assert_eq!(Some(MDEV_TYPE), dev.mdev_type.as_deref());
+    assert!(!dev.autostart);
+    assert!(!dev.active);
+}
+
+#[test]
+fn test_start_existing_attrs() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let mut test = TestEnvironment::new("start", "existing-attrs")
+        .with_parent(PARENT, MDEV_TYPE)
+        .with_active_device(UUID, PARENT, MDEV_TYPE);
+    test.interpolate_attrs = true;
+    let devpath = test.mdev_base().join(UUID);
+    fs::write(devpath.join("foo"), "stale\n").unwrap();
+    fs::write(devpath.join("bar"), "1\n").unwrap();
+
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+    dev.attrs = vec![
+        ("bar".to_string(), "1".to_string()),
+        ("foo".to_string(), "fresh".to_string()),
+        ("baz".to_string(), "on".to_string()),
+    ];
+
+    let e = dev
+        .start(false, None)
+        .expect_err("starting an existing device should fail");
+    assert_eq!("Device already exists", e.to_string());
+
+    let e = dev
+        .start(true, None)
+        .expect_err("starting an existing device should fail");
+    assert_eq!(
+        "Device exists with different attributes: baz (expected 'on', not present), \
+         foo (expected 'fresh', found 'stale')",
+        e.to_string()
+    );
+
+    // matching attributes only report that the device exists
+    dev.attrs = vec![("bar".to_string(), "1".to_string())];
+    let e = dev
+        .start(true, None)
+        .expect_err("starting an existing device should fail");
+    assert_eq!("Device already exists", e.to_string());
+
+    // references are resolved before comparing with the live values
+    fs::write(devpath.join("qux"), "1\n").unwrap();
+    dev.attrs = vec![
+        ("bar".to_string(), "1".to_string()),
+        ("qux".to_string(), "${bar}".to_string()),
+    ];
+    let e = dev
+        .start(true, None)
+        .expect_err("starting an existing device should fail");
+    assert_eq!("Device already exists", e.to_string());
+}
+
+#[test]
+fn test_sysfs_names() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let mut test = TestEnvironment::new("sysfs-names", "default");
+    test.sysfs_names = SysfsNames {
+        supported_types: "supported",
+        create: "new",
+        available_instances: "instances",
+        remove: "delete",
+    };
+    let test = test.with_parent(PARENT, MDEV_TYPE);
+    let typedir = test
+        .parent_base()
+        .join(PARENT)
+        .join("supported")
+        .join(MDEV_TYPE);
+    assert!(typedir.join("instances").exists());
+
+    let types = crate::supported_types(&test, None).unwrap();
+    assert_eq!(1, types[PARENT][0].available_instances);
+
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+    dev.create(false).expect("Failed to create device");
+    assert_eq!(UUID, fs::read_to_string(typedir.join("new")).unwrap());
+    assert!(!typedir.join("create").exists());
+
+    test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+    dev.stop(false, None).expect("Failed to stop device");
+    let devpath = test.mdev_base().join(UUID);
+    assert_eq!("1", fs::read_to_string(devpath.join("delete")).unwrap());
+    assert!(!devpath.join("remove").exists());
+}
+
+#[test]
+fn test_stop_wait() {
+    use std::time::{Duration, Instant};
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("stop", "wait").with_active_device(UUID, PARENT, MDEV_TYPE);
+    let devpath = test.mdev_base().join(UUID);
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.load_from_sysfs().unwrap();
+
+    // the device never goes away
+    dev.stop(false, Some(Duration::from_millis(100)))
+        .expect_err("stop should fail if the device is still there");
+    assert!(dev.active);
+
+    // the device goes away asynchronously, a while after it is removed
+    let delay = Duration::from_millis(200);
+    let remover = {
+        let devpath = devpath.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(delay);
+            fs::remove_file(devpath).unwrap();
+        })
+    };
+    let start = Instant::now();
+    dev.stop(false, Some(Duration::from_secs(10)))
+        .expect("stop should succeed once the device is gone");
+    assert!(start.elapsed() >= delay);
+    assert!(!devpath.exists());
+    assert!(!dev.active);
+    remover.join().unwrap();
+}
+
+#[test]
+fn test_list_output_dir() {
+    init();
+
+    const UUID1: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const UUID3: &str = "4a0a190f-dcf3-4def-9342-c48768f0c940";
+    const PARENT1: &str = "0000:00:02.0";
+    const PARENT2: &str = "0000:00:03.0";
+
+    let test = TestEnvironment::new("list", "output-dir")
+        .with_definition(
+            UUID1,
+            PARENT1,
+            &serde_json::json!({"mdev_type": "type-a", "start": "auto", "attrs": []}),
+        )
+        .with_definition(
+            UUID2,
+            PARENT1,
+            &serde_json::json!({"mdev_type": "type-b", "start": "manual", "attrs": []}),
+        )
+        .with_definition(
+            UUID3,
+            PARENT2,
+            &serde_json::json!({"mdev_type": "type-a", "start": "manual", "attrs": []}),
+        );
+
+    let outdir = Builder::new().prefix("mdevctl-dump").tempdir().unwrap();
+    crate::list_command(
+        &test,
+        true,
+        true,
+        false,
+        0,
+        false,
+        None,
+        None,
+        None,
+        None,
+        false,
+        Some(outdir.path().to_path_buf()),
+        None,
+        false,
+        None,
+        false,
+        &mut Vec::new(),
+    )
+    .expect("list command failed to dump to directory");
+
+    let mut files = Vec::new();
+    for parent in outdir.path().read_dir().unwrap() {
+        let parent = parent.unwrap();
+        for dev in parent.path().read_dir().unwrap() {
+            files.push(format!(
+                "{}/{}",
+                parent.file_name().to_string_lossy(),
+                dev.unwrap().file_name().to_string_lossy()
+            ));
+        }
+    }
+    files.sort();
+    assert_eq!(
+        vec![
+            format!("{}/{}", PARENT1, UUID2),
+            format!("{}/{}", PARENT1, UUID1),
+            format!("{}/{}", PARENT2, UUID3),
+        ],
+        files
+    );
+
+    // each file is identical to the stored definition
+    for file in files.iter() {
+        assert_eq!(
+            fs::read_to_string(test.persist_base().join(file)).unwrap(),
+            fs::read_to_string(outdir.path().join(file)).unwrap()
+        );
+    }
+
+    // dumping doesn't modify the configuration, so it works in a read-only environment
+    let mut test = test;
+    test.read_only = true;
+    let outdir = Builder::new().prefix("mdevctl-dump").tempdir().unwrap();
+    crate::list_command(
+        &test,
+        true,
+        true,
+        false,
+        0,
+        false,
+        None,
+        None,
+        None,
+        None,
+        false,
+        Some(outdir.path().to_path_buf()),
+        None,
+        false,
+        None,
+        false,
+        &mut Vec::new(),
+    )
+    .expect("list command failed to dump to directory in read-only mode");
+    for file in files.iter() {
+        assert_eq!(
+            fs::read_to_string(test.persist_base().join(file)).unwrap(),
+            fs::read_to_string(outdir.path().join(file)).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_error_to_json() {
+    init();
+
+    let err = std::io::Error::new(std::io::ErrorKind::NotFound, "No such file or directory");
+    let err = anyhow::Error::new(err)
+        .context("reading definition \"/etc/mdevctl.d/0000:00:03.0/foo\"")
+        .context("Failed to load device foo");
+
+    assert_eq!(
+        serde_json::json!({
+            "error": "Failed to load device foo",
+            "cause": [
+                "reading definition \"/etc/mdevctl.d/0000:00:03.0/foo\"",
+                "No such file or directory",
+            ],
+        }),
+        crate::error_to_json(&err)
+    );
+
+    let err = anyhow::anyhow!("Device already exists");
+    assert_eq!(
+        serde_json::json!({"error": "Device already exists", "cause": []}),
+        crate::error_to_json(&err)
+    );
+}
+
+#[test]
+fn test_unmanaged_active_devices() {
+    init();
+
+    const UUID1: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("list", "unmanaged")
+        .with_active_device(UUID1, PARENT, MDEV_TYPE)
+        .with_definition(
+            UUID1,
+            PARENT,
+            &serde_json::json!({"mdev_type": MDEV_TYPE, "start": "manual", "attrs": []}),
+        )
+        .with_active_device(UUID2, PARENT, MDEV_TYPE);
+
+    let devs = crate::unmanaged_active_devices(&test).unwrap();
+    assert_eq!(1, devs.len());
+    assert_eq!(UUID2, devs[0].uuid.to_hyphenated().to_string());
+    assert!(devs[0].active);
+    assert!(!devs[0].is_defined());
+}
+
+#[test]
+fn test_list_parent_regex() {
+    init();
+
+    const UUID1: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const PARENT1: &str = "0000:00:02.0";
+    const PARENT2: &str = "0000:3b:00.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("list", "parent-regex")
+        .with_active_device(UUID1, PARENT1, MDEV_TYPE)
+        .with_active_device(UUID2, PARENT2, MDEV_TYPE);
+
+    let list = |pattern: &str| {
+        let re = regex::Regex::new(pattern).unwrap();
+        crate::list_devices(&test, false, None, None, Some(&re), None, false, None)
+            .unwrap()
+            .0
+            .into_keys()
+            .collect::<Vec<String>>()
+    };
+    assert_eq!(vec![PARENT2.to_string()], list(r"^0000:3b:"));
+    assert_eq!(vec![PARENT1, PARENT2], list(r"^0000:[0-9a-f]{2}:"));
+    assert!(list(r"^0001:").is_empty());
+
+    crate::list_command(
+        &test,
+        false,
+        false,
+        false,
+        0,
+        false,
+        None,
+        None,
+        Some("0000:(00".to_string()),
+        None,
+        false,
+        None,
+        None,
+        false,
+        None,
+        false,
+        &mut Vec::new(),
+    )
+    .expect_err("an invalid regex should be rejected");
+}
+
+#[test]
+fn test_list_fail_empty() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:02.0";
+
+    let test = TestEnvironment::new("list", "fail-empty");
+    let list = |defined: bool, fail_empty: bool| {
+        crate::list_command(
+            &test,
+            defined,
+            false,
+            false,
+            0,
+            false,
+            None,
+            None,
+            None,
+            None,
+            false,
+            None,
+            None,
+            fail_empty,
+            None,
+            false,
+            &mut Vec::new(),
+        )
+    };
+    list(false, false).expect("an empty list should succeed by default");
+    list(false, true).expect_err("an empty list should fail with fail_empty");
+
+    test.populate_active_device(UUID, PARENT, "i915-GVTg_V5_4");
+    list(false, true).expect("a non-empty list should succeed with fail_empty");
+    // the filters apply before checking for an empty list
+    list(true, true).expect_err("no defined devices should fail with fail_empty");
+}
+
+#[test]
+fn test_list_null_output() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:02.0";
+
+    let test = TestEnvironment::new("list", "null-output");
+    let list = |fail_empty: bool, null_output: bool| {
+        let mut out = Vec::new();
+        let res = crate::list_command(
+            &test,
+            false,
+            false,
+            false,
+            0,
+            false,
+            None,
+            None,
+            None,
+            None,
+            false,
+            None,
+            None,
+            fail_empty,
+            None,
+            null_output,
+            &mut out,
+        );
+        (res, out)
+    };
+
+    let (res, out) = list(true, true);
+    res.expect_err("an empty list should still fail with fail_empty");
+    assert!(out.is_empty());
+
+    test.populate_active_device(UUID, PARENT, "i915-GVTg_V5_4");
+    let (res, out) = list(true, true);
+    res.expect("list with null output failed unexpectedly");
+    assert_eq!(0, out.len());
+    let (res, out) = list(true, false);
+    res.expect("list failed unexpectedly");
+    assert!(String::from_utf8(out).unwrap().contains(UUID));
+}
+
+#[test]
+fn test_list_watch() {
+    use anyhow::anyhow;
+    use std::time::Duration;
+
+    init();
+
+    let mut frames = 0;
+    let mut output = Vec::new();
+    crate::watch(&mut output, Duration::from_millis(1), Some(3), || {
+        frames += 1;
+        match frames {
+            2 => Err(anyhow!("device vanished")),
+            n => Ok(format!("frame {}", n)),
+        }
+    })
+    .expect("watch loop failed");
+    assert_eq!(3, frames);
+
+    // each frame is drawn on a cleared screen, and a failed frame doesn't end the loop
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(
+        vec!["", "frame 1\n", "Error: device vanished\n", "frame 3\n"],
+        output.split(crate::CLEAR_SCREEN).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_list_parse() {
+    use crate::cli::{LsmdevOptions, MdevctlCommands};
+    use std::time::Duration;
+    use structopt::StructOpt;
+
+    init();
+
+    // a plain list must not trip over the defaulted --interval
+    match MdevctlCommands::from_iter_safe(["mdevctl", "list"]) {
+        Ok(MdevctlCommands::List(opts)) => {
+            assert!(!opts.watch);
+            assert_eq!(Duration::from_secs(2), opts.interval);
+        }
+        Ok(_) => panic!("'mdevctl list' parsed as another command"),
+        Err(e) => panic!("'mdevctl list' failed to parse: {}", e),
+    }
+    LsmdevOptions::from_iter_safe(["lsmdev"]).expect("plain lsmdev failed to parse");
+
+    let opts = LsmdevOptions::from_iter_safe(["lsmdev", "--watch", "--interval", "5"])
+        .expect("lsmdev --watch --interval failed to parse");
+    assert!(opts.watch);
+    assert_eq!(Duration::from_secs(5), opts.interval);
+
+    LsmdevOptions::from_iter_safe(["lsmdev", "--dumpjson", "--watch", "--output-dir", "/tmp"])
+        .expect_err("--watch should conflict with --output-dir");
+    LsmdevOptions::from_iter_safe(["lsmdev", "--watch", "--output", "null"])
+        .expect_err("--watch should conflict with --output");
+}
+
+#[test]
+fn test_null_output_parse() {
+    use crate::cli::MdevctlCommands;
+    use structopt::StructOpt;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+
+    for args in [
+        vec!["mdevctl", "list", "--output", "null"],
+        vec!["mdevctl", "types", "--output", "null"],
+        vec![
+            "mdevctl",
+            "types",
+            "-p",
+            "0000:00:02.0",
+            "--count",
+            "t",
+            "--output=null",
+        ],
+        vec!["mdevctl", "export", "-u", UUID, "--output", "null"],
+        vec!["mdevctl", "verify", "-u", UUID, "--output", "null"],
+    ] {
+        let output = match MdevctlCommands::from_iter_safe(&args) {
+            Ok(MdevctlCommands::List(list)) => list.output,
+            Ok(MdevctlCommands::Types { output, .. })
+            | Ok(MdevctlCommands::Export { output, .. })
+            | Ok(MdevctlCommands::Verify { output, .. }) => output,
+            Ok(_) => panic!("{:?} parsed as another command", args),
+            Err(e) => panic!("{:?} failed to parse: {}", args, e),
+        };
+        assert_eq!(Some("null"), output.as_deref());
+    }
+    // only null output is supported
+    assert!(MdevctlCommands::from_iter_safe(["mdevctl", "types", "--output", "json"]).is_err());
+}
+
+#[test]
+fn test_list_match_attr() {
+    init();
+
+    const UUID1: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const UUID3: &str = "b6f7e33f-ea28-4f9d-8c42-797ff0ec2888";
+    const PARENT1: &str = "0000:00:02.0";
+    const PARENT2: &str = "0000:3b:00.0";
+
+    let test = TestEnvironment::new("list", "match-attr")
+        .with_definition(
+            UUID1,
+            PARENT1,
+            &serde_json::json!({"mdev_type": "type-a", "start": "manual",
+                "attrs": [{"assign_adapter": "5"}, {"assign_domain": "0x47"}]}),
+        )
+        .with_definition(
+            UUID2,
+            PARENT1,
+            &serde_json::json!({"mdev_type": "type-a", "start": "manual",
+                "attrs": [{"assign_adapter": "6"}]}),
+        )
+        .with_definition(
+            UUID3,
+            PARENT2,
+            &serde_json::json!({"mdev_type": "type-a", "start": "manual", "attrs": []}),
+        );
+
+    let list = |pattern: &str| {
+        crate::list_devices(&test, true, None, None, None, Some(pattern), false, None)
+            .unwrap()
+            .0
+            .into_values()
+            .flatten()
+            .map(|dev| dev.uuid.to_string())
+            .collect::<Vec<String>>()
+    };
+    assert_eq!(vec![UUID1], list("assign_adapter=5"));
+    assert_eq!(vec![UUID1], list("assign_domain"));
+    assert_eq!(vec![UUID2, UUID1], list("assign_adapter"));
+    assert!(list("assign_adapter=7").is_empty());
+    assert!(list("assign_control_domain").is_empty());
+}
+
+#[test]
+fn test_write_attr_validation() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("write-attr", "validation")
+        .with_active_device(UUID, PARENT, MDEV_TYPE);
+    let attrpath = test.mdev_base().join(UUID).join("foo");
+    fs::write(&attrpath, "").unwrap();
+
+    let dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    // the newline appended to the value counts towards the maximum length
+    let max = test.max_attr_len() - 1;
+    dev.write_attr("foo", &"x".repeat(max))
+        .expect("writing a value of maximum length should succeed");
+
+    let e = dev
+        .write_attr("foo", &"y".repeat(max + 1))
+        .expect_err("writing an over-long value should fail");
+    assert!(e.to_string().contains("'foo'"));
+    let e = dev
+        .write_attr("foo", "on\0off")
+        .expect_err("writing a value with a NUL should fail");
+    assert!(e.to_string().contains("'foo'"));
+
+    // rejected values are never written
+    assert_eq!(
+        format!("{}\n", "x".repeat(max)),
+        fs::read_to_string(&attrpath).unwrap()
+    );
+}
+
+#[test]
+fn test_attr_max_len() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let mut test = TestEnvironment::new("write-attr", "max-len").with_parent(PARENT, MDEV_TYPE);
+    test.max_attr_len = 4;
+    let devpath = test.mdev_base().join(UUID);
+    fs::create_dir_all(&devpath).unwrap();
+    fs::write(devpath.join("foo"), "").unwrap();
+
+    // verify applies the same limit as the write, including the appended newline
+    let check = |test: &TestEnvironment, val: &str| {
+        let mut dev = MDev::new(test, Uuid::parse_str(UUID).unwrap());
+        dev.parent = Some(PARENT.to_string());
+        dev.mdev_type = Some(MDEV_TYPE.to_string());
+        dev.attrs = vec![("foo".to_string(), val.to_string())];
+        let verified = dev.verify().unwrap().is_empty();
+        let written = dev.write_attr("foo", val).is_ok();
+        assert_eq!(
+            verified, written,
+            "verify and write_attr disagree on {:?}",
+            val
+        );
+        written
+    };
+    assert!(check(&test, "xxx"));
+    assert!(check(&test, "xxx\n"));
+    assert!(!check(&test, "xxxx"));
+
+    // a limit of zero rejects any value rather than overflowing
+    test.max_attr_len = 0;
+    assert!(!check(&test, "x"));
+    assert!(!check(&test, "\n"));
+}
+
+#[test]
+fn test_write_attr_directory() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test =
+        TestEnvironment::new("write-attr", "directory").with_active_device(UUID, PARENT, MDEV_TYPE);
+    fs::create_dir_all(test.mdev_base().join(UUID).join("power")).unwrap();
+
+    let dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    let e = dev.write_attr("power", "1").unwrap_err();
+    assert_eq!(
+        "Attribute 'power' is not writable (it is a directory)",
+        e.to_string()
+    );
+}
+
+#[test]
+fn test_write_attr_newline() {
+    use crate::environment::AttrNewline;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let mut test =
+        TestEnvironment::new("write-attr", "newline").with_active_device(UUID, PARENT, MDEV_TYPE);
+    let devpath = test.mdev_base().join(UUID);
+    for attr in ["foo", "bar"] {
+        fs::write(devpath.join(attr), "").unwrap();
+    }
+    let read = |attr: &str| fs::read_to_string(devpath.join(attr)).unwrap();
+
+    // a newline is appended by default, but not doubled
+    assert_eq!(AttrNewline::Append, test.attr_newline("foo"));
+    let dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.write_attr("foo", "1").unwrap();
+    assert_eq!("1\n", read("foo"));
+    dev.write_attr("foo", "2\n").unwrap();
+    assert_eq!("2\n", read("foo"));
+
+    // values are written as given to attributes which are configured as raw
+    test.raw_attrs = vec!["bar".to_string()];
+    let dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.write_attr("bar", "1").unwrap();
+    assert_eq!("1", read("bar"));
+    dev.write_attr("foo", "3").unwrap();
+    assert_eq!("3\n", read("foo"));
+    dev.write_attr("bar", &"x".repeat(test.max_attr_len()))
+        .expect("a raw value may use the full length");
+
+    test.raw_attrs = vec!["*".to_string()];
+    let dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.write_attr("foo", "4").unwrap();
+    assert_eq!("4", read("foo"));
+}
+
+#[test]
+fn test_matrix_attrs() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "matrix";
+    const MDEV_TYPE: &str = "vfio_ap-passthrough";
+
+    assert_eq!(
+        vec!["0x10", "0x11", "0x12"],
+        crate::mdev::expand_attr_list("0x10-0x12").unwrap()
+    );
+    assert_eq!(
+        vec!["0x0a", "0x0b", "0x10", "0x11", "0x12", "7"],
+        crate::mdev::expand_attr_list("0x0a,0x0b,0x10-0x12,7").unwrap()
+    );
+    assert_eq!(
+        vec!["8", "9"],
+        crate::mdev::expand_attr_list("8-9").unwrap()
+    );
+    assert_eq!(
+        256,
+        crate::mdev::expand_attr_list("0x0-0xff").unwrap().len()
+    );
+    for invalid in [
+        "0x12-0x10",
+        "0x10-",
+        "-3",
+        "0x10-0x1g",
+        "1,,2",
+        "1-2-3",
+        "0x0-0xffffffff",
+        "0xfe-0x100",
+        "0x0-0xff,0x0-0x1",
+    ] {
+        crate::mdev::expand_attr_list(invalid)
+            .expect_err(&format!("'{}' should be rejected", invalid));
+    }
+
+    let test =
+        TestEnvironment::new("write-attr", "matrix").with_active_device(UUID, PARENT, MDEV_TYPE);
+    let attrpath = test.mdev_base().join(UUID).join("assign_adapter");
+    fs::write(&attrpath, "").unwrap();
+    let dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+
+    // each element is written in turn, so the attribute file is left with the last one
+    dev.write_attr("assign_adapter", "0x10-0x12").unwrap();
+    assert_eq!("0x12\n", fs::read_to_string(&attrpath).unwrap());
+
+    let e = dev
+        .write_attr("assign_adapter", "0x13-0x11")
+        .expect_err("an invalid range should be rejected");
+    assert!(e.to_string().contains("'assign_adapter'"));
+    assert_eq!("0x12\n", fs::read_to_string(&attrpath).unwrap());
+}
+
+#[test]
+fn test_verify() {
+    init();
+
+    const UUID1: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const PARENT: &str = "0000:00:02.0";
+
+    let mut test = TestEnvironment::new("verify", "default")
+        .with_parent(PARENT, "type-a")
+        .with_definition(
+            UUID1,
+            PARENT,
+            &serde_json::json!({"mdev_type": "type-a", "start": "manual",
+                "attrs": [{"assign_adapter": "0x10-0x12"}]}),
+        )
+        .with_definition(
+            UUID2,
+            PARENT,
+            &serde_json::json!({"mdev_type": "type-b", "start": "manual",
+                "attrs": [{"../foo": "1"}, {"bar": "${baz}"}]}),
+        );
+    test.interpolate_attrs = true;
+
+    let uuid = Uuid::parse_str(UUID1).unwrap();
+    let dev = crate::get_defined_device(&test, uuid, None).unwrap();
+    assert!(dev.verify().unwrap().is_empty());
+    crate::verify_command(&test, uuid, None, false).expect("a startable device should verify");
+    crate::verify_command(&test, uuid, None, true).expect("null output keeps the result");
+
+    let uuid = Uuid::parse_str(UUID2).unwrap();
+    let dev = crate::get_defined_device(&test, uuid, None).unwrap();
+    let problems = dev.verify().unwrap();
+    assert_eq!(3, problems.len(), "{:?}", problems);
+    assert!(problems[0].contains("does not support mdev type type-b"));
+    assert!(problems[1].contains("'../foo'"));
+    assert!(problems[2].contains("'baz'"));
+    crate::verify_command(&test, uuid, None, false).expect_err("an unsupported type should fail");
+    crate::verify_command(&test, uuid, None, true)
+        .expect_err("an unsupported type should fail with null output");
+
+    // nothing was started
+    assert!(!test.mdev_base().join(UUID1).exists());
+    assert!(!test.mdev_base().join(UUID2).exists());
+}
+
+#[test]
+fn test_validate_for_start() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:02.0";
+
+    let test = TestEnvironment::new("validate-for-start", "default").with_parent(PARENT, "type-a");
+
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.attrs.push(("../remove".to_string(), "1".to_string()));
+    let e = dev
+        .start(false, None)
+        .expect_err("an under-specified device should not start");
+    let msg = e.to_string();
+    assert!(msg.contains("no parent device"), "{}", msg);
+    assert!(msg.contains("no mdev type"), "{}", msg);
+    assert!(msg.contains("'../remove'"), "{}", msg);
+    assert!(!test.mdev_base().join(UUID).exists());
+
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some("type-a".to_string());
+    dev.attrs.clear();
+    dev.validate_for_start()
+        .expect("a fully specified device should validate");
+}
+
+#[test]
+fn test_device_api_validation() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT_AP: &str = "matrix";
+    const PARENT_CCW: &str = "0.0.26ab";
+    const PARENT_PCI: &str = "0000:00:02.0";
+
+    let test =
+        TestEnvironment::new("validate-for-start", "device-api").with_parent(PARENT_PCI, "type-a");
+    test.populate_parent_device(PARENT_AP, "vfio_ap-passthrough", 1, "vfio-ap", "", None);
+    test.populate_parent_device(PARENT_CCW, "vfio_ccw-io", 1, "vfio-ccw", "", None);
+
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT_AP.to_string());
+    dev.mdev_type = Some("vfio_ap-passthrough".to_string());
+    dev.attrs = vec![("assign_adapter".to_string(), "5".to_string())];
+
+    // a vfio-ap device without any domain is flagged
+    let problems = dev.api_problems();
+    assert_eq!(1, problems.len());
+    assert!(problems[0].contains("'assign_domain'"));
+    let e = dev
+        .start(false, None)
+        .expect_err("a vfio-ap device without a domain should not start");
+    assert!(e.to_string().contains("'assign_domain'"));
+    assert!(!test.mdev_base().join(UUID).exists());
+    assert!(dev
+        .verify()
+        .unwrap()
+        .iter()
+        .any(|p| p.contains("'assign_domain'")));
+
+    dev.attrs
+        .push(("assign_domain".to_string(), "0x47".to_string()));
+    assert!(dev.api_problems().is_empty());
+
+    // vfio-ccw devices take no attributes
+    dev.parent = Some(PARENT_CCW.to_string());
+    dev.mdev_type = Some("vfio_ccw-io".to_string());
+    assert_eq!(2, dev.api_problems().len());
+    dev.attrs.clear();
+    assert!(dev.api_problems().is_empty());
+
+    // other device APIs accept any attributes
+    dev.parent = Some(PARENT_PCI.to_string());
+    dev.mdev_type = Some("type-a".to_string());
+    dev.attrs = vec![("foo".to_string(), "bar".to_string())];
+    assert!(dev.api_problems().is_empty());
+}
+
+#[test]
+fn test_create_instance_counts() {
+    use crate::mdev::instance_usage_problem;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:02.0";
+    const MDEV_TYPE: &str = "type-a";
+
+    let test = TestEnvironment::new("create", "instance-counts");
+    test.populate_parent_device(PARENT, MDEV_TYPE, 3, "vfio-pci", "", None);
+
+    // the fake sysfs doesn't update the count when a device is created
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+    assert_eq!((3, Some(3)), dev.create(false).unwrap());
+    assert!(dev.active);
+
+    assert_eq!(None, instance_usage_problem(3, 2));
+    let msg = instance_usage_problem(3, 3).expect("an unchanged count should be flagged");
+    assert!(msg.contains("3 -> 3"), "{}", msg);
+    assert!(instance_usage_problem(3, 4).is_some());
+}
+
+#[test]
+fn test_create_no_instances() {
+    init();
+
+    const UUID: &[&str] = &[
+        "3eee6cd9-35ad-43bd-9be1-14ee2b7389c9",
+        "4a0a190f-dcf3-4def-9342-c48768f0c940",
+        "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9",
+        "59e8b599-afdd-4766-a59e-415ef4f5e492",
+        "b6f7e33f-ea28-4f9d-8c42-797ff0ec2888",
+    ];
+    const PARENT: &str = "0000:00:02.0";
+    const PARENT2: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "type-a";
+
+    let test = TestEnvironment::new("create", "no-instances");
+    test.populate_active_device(UUID[0], PARENT, MDEV_TYPE);
+    test.populate_active_device(UUID[1], PARENT, MDEV_TYPE);
+    // devices of another type or on another parent are not counted as in use
+    test.populate_active_device(UUID[2], PARENT, "type-b");
+    test.populate_active_device(UUID[4], PARENT2, MDEV_TYPE);
+    test.populate_parent_device(PARENT, MDEV_TYPE, 0, "vfio-pci", "", None);
+    test.populate_parent_device(PARENT2, MDEV_TYPE, 0, "vfio-pci", "", None);
+
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID[3]).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+    let e = dev.create(false).unwrap_err();
+    assert_eq!(
+        "No available instances of type-a on 0000:00:02.0 (0 available; 2 in use)",
+        e.to_string()
+    );
+    assert!(dev.verify().unwrap().contains(&e.to_string()));
+
+    dev.parent = Some(PARENT2.to_string());
+    let e = dev.create(false).unwrap_err();
+    assert_eq!(
+        "No available instances of type-a on 0000:00:03.0 (0 available; 1 in use)",
+        e.to_string()
+    );
+}
+
+#[test]
+fn test_no_mdev_support() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:02.0";
+    const MDEV_TYPE: &str = "type-a";
+
+    // a kernel built without mdev support has neither the bus nor the class of parent devices
+    let test = TestEnvironment::new("create", "no-mdev-support");
+    fs::remove_dir_all(test.parent_base()).unwrap();
+    fs::remove_dir_all(test.mdev_base()).unwrap();
+
+    let expect_unsupported = |e: anyhow::Error| {
+        assert!(
+            e.to_string()
+                .contains("kernel does not appear to support mediated devices"),
+            "{}",
+            e
+        )
+    };
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+    expect_unsupported(dev.create(false).unwrap_err());
+    expect_unsupported(crate::supported_types(&test, None).unwrap_err());
+    expect_unsupported(crate::active_devices(&test).unwrap_err());
+
+    // the bus alone is enough, as parent devices may not have registered yet
+    fs::create_dir_all(test.mdev_base()).unwrap();
+    assert!(crate::supported_types(&test, None).unwrap().is_empty());
+    assert!(crate::active_devices(&test).unwrap().is_empty());
+}
+
+#[test]
+fn test_start_concurrently() {
+    use crate::mdev::lock_device;
+    use std::time::{Duration, Instant};
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:02.0";
+    const MDEV_TYPE: &str = "type-a";
+    const ALIAS: &str = "type-a-alias";
+
+    // the device is started by another process, which holds the lock, while this one waits for
+    // the lock. Once it gets the lock, it finds the device running.
+    let race = |testcase: &str, loser_type: &str, winner_type: &str| {
+        let test = TestEnvironment::new("start", testcase).with_parent(PARENT, MDEV_TYPE);
+        let typesdir = test
+            .parent_base()
+            .join(PARENT)
+            .join(test.sysfs_names().supported_types);
+        std::os::unix::fs::symlink(typesdir.join(MDEV_TYPE), typesdir.join(ALIAS)).unwrap();
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let lock = lock_device(&test, &uuid, None).unwrap();
+        let result = std::thread::scope(|s| {
+            let loser = s.spawn(|| {
+                let mut dev = MDev::new(&test, uuid);
+                dev.parent = Some(PARENT.to_string());
+                dev.mdev_type = Some(loser_type.to_string());
+                dev.start(false, None).map(|_| dev.active)
+            });
+            std::thread::sleep(Duration::from_millis(200));
+            test.populate_active_device(UUID, PARENT, winner_type);
+            drop(lock);
+            loser.join().unwrap()
+        });
+        let create_path = test
+            .parent_base()
+            .join(PARENT)
+            .join(test.sysfs_names().supported_types)
+            .join(MDEV_TYPE)
+            .join(test.sysfs_names().create);
+        assert!(!create_path.exists(), "the device was created twice");
+        result
+    };
+
+    // the same device was started, so there is nothing left to do
+    assert!(
+        race("concurrent-same", MDEV_TYPE, MDEV_TYPE).expect("a concurrent start should succeed")
+    );
+    // also if this device names its type by an alias
+    assert!(race("concurrent-alias", ALIAS, MDEV_TYPE)
+        .expect("a concurrent start by an alias should succeed"));
+    // a different device is a conflict
+    let e = race("concurrent-conflict", MDEV_TYPE, "type-b").unwrap_err();
+    assert!(e.to_string().contains("started concurrently"), "{}", e);
+
+    // waiting for the lock gives up at the deadline
+    let test = TestEnvironment::new("start", "lock-deadline").with_parent(PARENT, MDEV_TYPE);
+    let uuid = Uuid::parse_str(UUID).unwrap();
+    let _lock = lock_device(&test, &uuid, None).unwrap();
+    let mut dev = MDev::new(&test, uuid);
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+    let e = dev
+        .start(false, Some(Instant::now() + Duration::from_millis(100)))
+        .expect_err("starting a locked device should time out");
+    assert!(e.to_string().contains("Timed out"), "{}", e);
+    assert!(!dev.active);
+    assert!(!test.mdev_base().join(UUID).exists());
+}
+
+#[test]
+fn test_start_deadline() {
+    use std::time::{Duration, Instant};
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:02.0";
+    const MDEV_TYPE: &str = "type-a";
+
+    let test = TestEnvironment::new("start", "deadline").with_parent(PARENT, MDEV_TYPE);
+    // stands in for the device directory the kernel creates, so that attributes can be written
+    let devpath = test.mdev_base().join(UUID);
+    fs::create_dir_all(&devpath).unwrap();
+    fs::write(devpath.join("foo"), "").unwrap();
+
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+    dev.attrs = vec![("foo".to_string(), "1".to_string())];
+
+    let create = test
+        .parent_base()
+        .join(PARENT)
+        .join(test.sysfs_names().supported_types)
+        .join(MDEV_TYPE)
+        .join(test.sysfs_names().create);
+    let remove = devpath.join(test.sysfs_names().remove);
+
+    // a deadline that has already passed fails before the device is created
+    let e = dev
+        .start(false, Some(Instant::now()))
+        .expect_err("starting past the deadline should fail");
+    assert!(e.to_string().contains("Timed out"));
+    assert!(!dev.active);
+    assert!(!create.exists());
+    assert_eq!("", fs::read_to_string(devpath.join("foo")).unwrap());
+    assert!(!remove.exists());
+
+    // the same holds for a device without attributes
+    let mut bare = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    bare.parent = Some(PARENT.to_string());
+    bare.mdev_type = Some(MDEV_TYPE.to_string());
+    let e = bare
+        .start(false, Some(Instant::now()))
+        .expect_err("starting a device without attributes past the deadline should fail");
+    assert!(e.to_string().contains("Timed out"));
+    assert!(!bare.active);
+    assert!(!create.exists());
+    assert!(!remove.exists());
+
+    dev.start(false, Some(Instant::now() + Duration::from_secs(60)))
+        .expect("starting within the deadline should succeed");
+    assert_eq!("1\n", fs::read_to_string(devpath.join("foo")).unwrap());
+    assert!(!remove.exists());
+}
+
+#[test]
+fn test_start_retries() {
+    use crate::mdev::retry_op;
+    use anyhow::{anyhow, Result};
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:02.0";
+    const MDEV_TYPE: &str = "type-a";
+
+    let test = TestEnvironment::new("start", "retries");
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+
+    // a write to sysfs failing because the device is busy
+    let busy = || -> Result<()> {
+        Err(anyhow::Error::new(io::Error::from_raw_os_error(16)).context("Failed to create"))
+    };
+
+    // errors which won't go away by trying again are reported immediately
+    let mut calls = 0;
+    retry_op(3, Duration::from_millis(1), None, || {
+        calls += 1;
+        dev.start(false, None)
+    })
+    .expect_err("start without a parent should fail");
+    assert_eq!(1, calls);
+
+    // creating the device fails while it is busy, until the third attempt
+    test.populate_parent_device(PARENT, MDEV_TYPE, 1, "vfio-pci", "", None);
+    let mut calls = 0;
+    retry_op(3, Duration::from_millis(1), None, || {
+        calls += 1;
+        match calls {
+            3 => dev.start(false, None),
+            _ => busy(),
+        }
+    })
+    .expect("start should succeed after retries");
+    assert_eq!(3, calls);
+    assert!(dev.active);
+
+    // give up once the retries are used up
+    let mut calls = 0;
+    let res: Result<()> = retry_op(2, Duration::from_millis(1), None, || {
+        calls += 1;
+        busy()
+    });
+    assert!(res.is_err());
+    assert_eq!(3, calls);
+
+    // no retries past the deadline
+    let mut calls = 0;
+    let res: Result<()> = retry_op(2, Duration::from_millis(1), Some(Instant::now()), || {
+        calls += 1;
+        busy()
+    });
+    assert!(res.is_err());
+    assert_eq!(1, calls);
+
+    // nor are conflicts with an existing device
+    let mut calls = 0;
+    let res: Result<()> = retry_op(2, Duration::from_millis(1), None, || {
+        calls += 1;
+        Err(anyhow!("Device already exists"))
+    });
+    assert!(res.is_err());
+    assert_eq!(1, calls);
+}
+
+#[test]
+fn test_delete_attribute_by_name() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+
+    let test = TestEnvironment::new("modify", "delattr-name");
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    let attrs = vec![
+        ("assign_adapter".to_string(), "5".to_string()),
+        ("assign_domain".to_string(), "0xab".to_string()),
+        ("assign_adapter".to_string(), "6".to_string()),
+        ("assign_control_domain".to_string(), "0xab".to_string()),
+    ];
+
+    // a single occurrence removes only the first match
+    dev.attrs = attrs.clone();
+    assert_eq!(
+        1,
+        dev.delete_attribute_by_name("assign_adapter", false)
+            .unwrap()
+    );
+    assert_eq!(
+        vec![attrs[1].clone(), attrs[2].clone(), attrs[3].clone()],
+        dev.attrs
+    );
+
+    // all occurrences of a repeated key
+    dev.attrs = attrs.clone();
+    assert_eq!(
+        2,
+        dev.delete_attribute_by_name("assign_adapter", true)
+            .unwrap()
+    );
+    assert_eq!(vec![attrs[1].clone(), attrs[3].clone()], dev.attrs);
+
+    // no match is an error and leaves the attributes untouched
+    dev.attrs = attrs.clone();
+    dev.delete_attribute_by_name("assign_usage", false)
+        .expect_err("deleting a nonexistent attribute should fail");
+    dev.delete_attribute_by_name("assign_usage", true)
+        .expect_err("deleting a nonexistent attribute should fail");
+    assert_eq!(attrs, dev.attrs);
+}
+
+#[test]
+fn test_dedup_attributes() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "vfio_ap-passthrough";
+
+    let def = serde_json::json!({"mdev_type": MDEV_TYPE, "start": "manual",
+        "attrs": [{"assign_adapter": "5"}, {"assign_domain": "0xab"},
+            {"assign_adapter": "5"}, {"assign_adapter": "6"}, {"assign_domain": "0xab"}]});
+    let test = TestEnvironment::new("modify", "dedup").with_definition(UUID, PARENT, &def);
+    let uuid = Uuid::parse_str(UUID).unwrap();
+    crate::modify_command(
+        &test, uuid, None, None, None, false, None, false, None, None, false, false, false, false,
+        None, true, false,
+    )
+    .expect("modify --dedup failed unexpectedly");
+
+    // the first occurrence of each pair is kept in place, repeated keys with other values stay
+    let mut dev = crate::get_defined_device(&test, uuid, None).unwrap();
+    assert_eq!(
+        vec![
+            ("assign_adapter".to_string(), "5".to_string()),
+            ("assign_domain".to_string(), "0xab".to_string()),
+            ("assign_adapter".to_string(), "6".to_string()),
+        ],
+        dev.attrs
+    );
+    assert_eq!(0, dev.dedup_attributes());
+}
+
+#[test]
+fn test_active_attrs() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("active-attrs", "default")
+        .with_active_device(UUID, PARENT, MDEV_TYPE)
+        .with_definition(
+            UUID,
+            PARENT,
+            &serde_json::json!({
+                "mdev_type": MDEV_TYPE,
+                "start": "manual",
+                "attrs": [{"foo": "defined"}, {"bar": "1"}]
+            }),
+        );
+    let devpath = test.mdev_base().join(UUID);
+    fs::write(devpath.join("foo"), "live\n").unwrap();
+    fs::write(devpath.join("bar"), "1\n").unwrap();
+
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    assert_eq!(None, dev.active_attrs());
+    dev.load_from_sysfs().unwrap();
+    dev.load_definition().unwrap();
+    assert!(dev.active);
+    assert!(dev.is_defined());
+
+    let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+    assert_eq!(vec![pair("foo", "defined"), pair("bar", "1")], dev.attrs);
+    assert_eq!(
+        Some(vec![pair("bar", "1"), pair("foo", "live")]),
+        dev.active_attrs()
+    );
+
+    // the live values are read when they are needed rather than when the device is loaded
+    fs::write(devpath.join("foo"), "changed\n").unwrap();
+    assert_eq!(
+        Some(vec![pair("bar", "1"), pair("foo", "changed")]),
+        dev.active_attrs()
+    );
+}
+
+#[test]
+fn test_symlinked_parent() {
+    use std::os::unix::fs::symlink;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const LINK: &str = "gpu0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("symlinked-parent", "default")
+        .with_active_device(UUID, PARENT, MDEV_TYPE)
+        .with_definition(
+            UUID,
+            LINK,
+            &serde_json::json!({"mdev_type": MDEV_TYPE, "start": "manual", "attrs": []}),
+        );
+    symlink(
+        test.parent_base().join(PARENT),
+        test.parent_base().join(LINK),
+    )
+    .unwrap();
+
+    let uuid = Uuid::parse_str(UUID).unwrap();
+    let dev = crate::get_defined_device(&test, uuid, Some(&LINK.to_string())).unwrap();
+    assert!(dev.active);
+    assert_eq!(Some(LINK), dev.parent.as_deref());
+    assert!(dev.is_defined());
+
+    // a name which is neither the parent nor a link to it doesn't match
+    let mut dev = MDev::new(&test, uuid);
+    dev.parent = Some("0000:00:02.0".to_string());
+    dev.load_from_sysfs().unwrap();
+    assert!(!dev.active);
+}
+
+#[test]
+fn test_resolve_parent() {
+    use crate::mdev::resolve_parent;
+    use std::os::unix::fs::symlink;
+
+    init();
+
+    const PARENT1: &str = "0000:00:02.0";
+    const PARENT2: &str = "0000:00:03.0";
+    const LINK: &str = "gpu0";
+
+    let test = TestEnvironment::new("resolve-parent", "default");
+    let e = resolve_parent(&test, PARENT1).expect_err("no parents should be found");
+    assert!(e.to_string().contains("no parent devices available"));
+
+    let test = test
+        .with_parent(PARENT1, "type-a")
+        .with_parent(PARENT2, "type-b");
+    symlink(
+        test.parent_base().join(PARENT2),
+        test.parent_base().join(LINK),
+    )
+    .unwrap();
+
+    assert_eq!(PARENT1, resolve_parent(&test, PARENT1).unwrap());
+    assert_eq!(PARENT2, resolve_parent(&test, LINK).unwrap());
+
+    let e = resolve_parent(&test, "0000:00:04.0").expect_err("unknown parent should fail");
+    assert!(e
+        .to_string()
+        .contains(&format!("{}, {}, {}", PARENT1, PARENT2, LINK)));
+    resolve_parent(&test, "../0000:00:02.0").expect_err("paths should be rejected");
+    resolve_parent(&test, ".").expect_err("the parent directory itself should be rejected");
+    resolve_parent(&test, "..").expect_err("the directory above should be rejected");
+}
+
+#[test]
+fn test_aliased_type() {
+    use std::os::unix::fs::symlink;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+    const ALIAS: &str = "i915-alias";
+
+    let test = TestEnvironment::new("aliased-type", "default")
+        .with_active_device(UUID, PARENT, MDEV_TYPE)
+        .with_definition(
+            UUID,
+            PARENT,
+            &serde_json::json!({"mdev_type": ALIAS, "start": "manual", "attrs": []}),
+        );
+    let typesdir = test
+        .parent_base()
+        .join(PARENT)
+        .join(test.sysfs_names().supported_types);
+    symlink(typesdir.join(MDEV_TYPE), typesdir.join(ALIAS)).unwrap();
+    let uuid = Uuid::parse_str(UUID).unwrap();
+
+    // the device's type link points to the canonical type, the alias is resolved via the parent
+    let dev = crate::get_defined_device(&test, uuid, None).unwrap();
+    assert!(dev.active);
+    assert_eq!(Some(ALIAS), dev.mdev_type.as_deref());
+
+    // the device's type link points to an alias which isn't among the parent's types
+    let aliasdir = test.parent_base().join(PARENT).join("aliases");
+    fs::create_dir(&aliasdir).unwrap();
+    fs::remove_file(typesdir.join(ALIAS)).unwrap();
+    symlink(typesdir.join(MDEV_TYPE), aliasdir.join(ALIAS)).unwrap();
+    let typelink = test.mdev_base().join(UUID).join("mdev_type");
+    fs::remove_file(&typelink).unwrap();
+    symlink(aliasdir.join(ALIAS), &typelink).unwrap();
+    let dev = crate::get_defined_device(&test, uuid, None).unwrap();
+    assert!(dev.active);
+    assert_eq!(Some(ALIAS), dev.mdev_type.as_deref());
+
+    // the canonical name still matches, other types don't
+    let mut dev = MDev::new(&test, uuid);
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+    dev.load_from_sysfs().unwrap();
+    assert!(dev.active);
+    let mut dev = MDev::new(&test, uuid);
+    dev.mdev_type = Some("i915-GVTg_V5_8".to_string());
+    dev.load_from_sysfs().unwrap();
+    assert!(!dev.active);
+
+    // creating the device again by an alias among the parent's types finds that it already
+    // exists with the same type
+    fs::remove_file(&typelink).unwrap();
+    symlink(typesdir.join(MDEV_TYPE), &typelink).unwrap();
+    symlink(typesdir.join(MDEV_TYPE), typesdir.join(ALIAS)).unwrap();
+    let mut dev = MDev::new(&test, uuid);
+    dev.parent = Some(PARENT.to_string());
+    dev.mdev_type = Some(ALIAS.to_string());
+    let e = dev.create(true).unwrap_err();
+    assert_eq!("Device already exists", e.to_string());
+    dev.mdev_type = Some("i915-GVTg_V5_8".to_string());
+    let e = dev.create(true).unwrap_err();
+    assert_eq!("Device exists with different type", e.to_string());
+}
+
+#[test]
+fn test_attr_help() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("attr-help", "default")
+        .with_active_device(UUID, PARENT, MDEV_TYPE)
+        .with_definition(
+            UUID,
+            PARENT,
+            &serde_json::json!({
+                "mdev_type": MDEV_TYPE,
+                "start": "manual",
+                "attrs": [{"foo": "1"}, {"bar": "2"}, {"baz": "3"}]
+            }),
+        );
+    let devpath = test.mdev_base().join(UUID);
+    fs::write(devpath.join("foo_help"), "Enable foo\nMore details\n").unwrap();
+    fs::write(devpath.join("baz_help"), "\n").unwrap();
+
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.load_from_sysfs().unwrap();
+    dev.load_definition().unwrap();
+    let expected = format!(
+        "{} {} {} manual (defined)\n  Attrs:\n    @{{0}}: {{\"foo\":\"1\"}}  # Enable foo\n    \
+         @{{1}}: {{\"bar\":\"2\"}}\n    @{{2}}: {{\"baz\":\"3\"}}\n",
+        UUID, PARENT, MDEV_TYPE
+    );
+    assert_eq!(expected, dev.to_text(FormatType::Active, 1, false).unwrap());
+
+    // descriptions are only shown in verbose mode
+    let expected = format!("{} {} {} manual (defined)\n", UUID, PARENT, MDEV_TYPE);
+    assert_eq!(expected, dev.to_text(FormatType::Active, 0, false).unwrap());
+}
+
+#[test]
+fn test_diff_attrs() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("diff-attrs", "default")
+        .with_active_device(UUID, PARENT, MDEV_TYPE)
+        .with_definition(
+            UUID,
+            PARENT,
+            &serde_json::json!({
+                "mdev_type": MDEV_TYPE,
+                "start": "manual",
+                "attrs": [{"foo": "defined"}, {"bar": "1"}]
+            }),
+        );
+    let devpath = test.mdev_base().join(UUID);
+    fs::write(devpath.join("foo"), "live\n").unwrap();
+    fs::write(devpath.join("bar"), "1\n").unwrap();
+
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.load_from_sysfs().unwrap();
+    dev.load_definition().unwrap();
+    let expected = format!(
+        "{} {} {} manual (defined)\n  Attrs:\n  * @{{0}}: {{\"foo\":\"defined\"}}  \
+         (active: \"live\")\n    @{{1}}: {{\"bar\":\"1\"}}\n",
+        UUID, PARENT, MDEV_TYPE
+    );
+    assert_eq!(expected, dev.to_text(FormatType::Active, 1, true).unwrap());
+
+    // without diff_attrs, or for a device that isn't active, nothing is marked
+    let expected = format!(
+        "{} {} {} manual (defined)\n  Attrs:\n    @{{0}}: {{\"foo\":\"defined\"}}\n    \
+         @{{1}}: {{\"bar\":\"1\"}}\n",
+        UUID, PARENT, MDEV_TYPE
+    );
+    assert_eq!(expected, dev.to_text(FormatType::Active, 1, false).unwrap());
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.parent = Some(PARENT.to_string());
+    dev.load_definition().unwrap();
+    assert!(!dev
+        .to_text(FormatType::Defined, 1, true)
+        .unwrap()
+        .contains('*'));
+}
+
+#[test]
+fn test_verbose_levels() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("verbose-levels", "default")
+        .with_active_device(UUID, PARENT, MDEV_TYPE)
+        .with_definition(
+            UUID,
+            PARENT,
+            &serde_json::json!({
+                "mdev_type": MDEV_TYPE,
+                "start": "manual",
+                "attrs": [{"foo": "defined"}]
+            }),
+        );
+    let typedir = test
+        .parent_base()
+        .join(PARENT)
+        .join(test.sysfs_names().supported_types)
+        .join(MDEV_TYPE);
+    fs::write(typedir.join("device_api"), "vfio-pci\n").unwrap();
+    fs::write(test.mdev_base().join(UUID).join("foo"), "live\n").unwrap();
+
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.load_from_sysfs().unwrap();
+    dev.load_definition().unwrap();
+
+    let header = format!("{} {} {} manual (defined)\n", UUID, PARENT, MDEV_TYPE);
+    let api = "  Device API: vfio-pci\n";
+    let attrs = "  Attrs:\n    @{0}: {\"foo\":\"defined\"}\n";
+    let drift = "  Attrs:\n  * @{0}: {\"foo\":\"defined\"}  (active: \"live\")\n";
+    let text = |level: u8| dev.to_text(FormatType::Active, level, false).unwrap();
+    assert_eq!(header, text(0));
+    assert_eq!(format!("{}{}", header, attrs), text(1));
+    assert_eq!(format!("{}{}{}", header, api, attrs), text(2));
+    assert_eq!(format!("{}{}{}", header, api, drift), text(3));
+    assert_eq!(text(3), text(4));
+}
+
+#[test]
+fn test_define_if_changed() {
+    use crate::environment::PersistLayout;
+    use std::os::unix::fs::MetadataExt;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    for layout in [PersistLayout::PerDevice, PersistLayout::PerParent] {
+        let mut test = TestEnvironment::new("define", "if-changed");
+        test.persist_layout = layout;
+        let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+        dev.parent = Some(PARENT.to_string());
+        dev.mdev_type = Some(MDEV_TYPE.to_string());
+        dev.attrs = vec![("foo".to_string(), "1".to_string())];
+        let path = dev.persist_path().unwrap();
+        // definitions are replaced by a new file, so a write shows as a new inode
+        let inode = || fs::metadata(&path).unwrap().ino();
+
+        assert!(dev.define_if_changed().unwrap());
+        let written = inode();
+        assert!(!dev.define_if_changed().unwrap());
+        assert_eq!(written, inode());
+
+        dev.attrs[0].1 = "2".to_string();
+        assert!(dev.define_if_changed().unwrap());
+        assert_ne!(written, inode());
+        let def = crate::get_defined_device(&test, dev.uuid, None).unwrap();
+        assert_eq!(dev.attrs, def.attrs);
+    }
+}
+
+#[test]
+fn test_define_start() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let uuid = Uuid::parse_str(UUID).unwrap();
+    let define = |test: &TestEnvironment, mdev_type: &str, keep_on_failure: bool| {
+        crate::define_command(
+            test,
+            Some(uuid),
+            false,
+            Some(PARENT.to_string()),
+            Some(mdev_type.to_string()),
+            None,
+            true,
+            keep_on_failure,
+            false,
+            false,
+        )
+    };
+
+    // the device is defined and started
+    let test = TestEnvironment::new("define", "start").with_parent(PARENT, MDEV_TYPE);
+    define(&test, MDEV_TYPE, false).expect("define --start failed unexpectedly");
+    let create_path = test
+        .parent_base()
+        .join(PARENT)
+        .join(test.sysfs_names().supported_types)
+        .join(MDEV_TYPE)
+        .join(test.sysfs_names().create);
+    assert_eq!(UUID, fs::read_to_string(create_path).unwrap());
+    let dev = crate::get_defined_device(&test, uuid, Some(&PARENT.to_string())).unwrap();
+    assert_eq!(Some(MDEV_TYPE), dev.mdev_type.as_deref());
+
+    // the definition is rolled back if the device fails to start
+    let test = TestEnvironment::new("define", "start-rollback").with_parent(PARENT, MDEV_TYPE);
+    define(&test, "unsupported-type", false).expect_err("define --start should have failed");
+    crate::get_defined_device(&test, uuid, Some(&PARENT.to_string()))
+        .expect_err("definition should have been removed");
+
+    // ... unless it is explicitly kept
+    let test = TestEnvironment::new("define", "start-keep").with_parent(PARENT, MDEV_TYPE);
+    define(&test, "unsupported-type", true).expect_err("define --start should have failed");
+    let dev = crate::get_defined_device(&test, uuid, Some(&PARENT.to_string()))
+        .expect("definition should have been kept");
+    assert!(!dev.active);
+}
+
+#[test]
+fn test_success_output() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let uuid = Uuid::parse_str(UUID).unwrap();
+    let test = TestEnvironment::new("define", "print-json");
+    crate::define_command(
+        &test,
+        Some(uuid),
+        false,
+        Some(PARENT.to_string()),
+        Some(MDEV_TYPE.to_string()),
+        None,
+        false,
+        false,
+        false,
+        true,
+    )
+    .expect("define failed unexpectedly");
+
+    let dev = crate::get_defined_device(&test, uuid, Some(&PARENT.to_string())).unwrap();
+    let output = crate::success_output(&dev, true, true).unwrap().unwrap();
+    let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(
+        serde_json::json!({UUID: {"mdev_type": MDEV_TYPE, "start": "manual", "attrs": []}}),
+        json
+    );
+
+    // without json, only a generated uuid is printed
+    assert_eq!(
+        Some(UUID.to_string()),
+        crate::success_output(&dev, false, true).unwrap()
+    );
+    assert_eq!(None, crate::success_output(&dev, false, false).unwrap());
+}
+
+#[test]
+fn test_list_deadline() {
+    use std::time::{Duration, Instant};
+
+    init();
+
+    const UUID1: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const PARENT: &str = "0000:00:03.0";
+
+    let def = serde_json::json!({"mdev_type": "type-a", "start": "manual", "attrs": []});
+    let test = TestEnvironment::new("list", "deadline")
+        .with_definition(UUID1, PARENT, &def)
+        .with_definition(UUID2, PARENT, &def);
+
+    let (devices, timed_out) =
+        crate::list_devices(&test, true, None, None, None, None, false, None).unwrap();
+    assert!(!timed_out);
+    assert_eq!(2, devices[PARENT].len());
+
+    // a deadline that has already passed returns without loading anything
+    let (devices, timed_out) = crate::list_devices(
+        &test,
+        true,
+        None,
+        None,
+        None,
+        None,
+        false,
+        Some(Instant::now()),
+    )
+    .unwrap();
+    assert!(timed_out);
+    assert!(devices.is_empty());
+
+    // simulate an enumeration where every lookup is slow
+    let delay = Duration::from_millis(50);
+    let slow = (0..100).inspect(|_| std::thread::sleep(delay));
+    let start = Instant::now();
+    let mut iter = crate::UntilDeadline::new(slow, Some(start + delay * 3));
+    let found: Vec<i32> = iter.by_ref().collect();
+    assert!(iter.timed_out);
+    assert!(!found.is_empty() && found.len() <= 3);
+    // the enumeration is abandoned at most one lookup after the deadline
+    assert!(start.elapsed() < delay * 5);
+}
+
+#[test]
+fn test_resolved_attrs() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+
+    let mut test = TestEnvironment::new("resolved-attrs", "default");
+    let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+
+    // without interpolation values are passed through unchanged
+    let raw = vec![pair("token", "abc"), pair("shared", "$${token} ${token")];
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.attrs = raw.clone();
+    assert_eq!(raw, dev.resolved_attrs().unwrap());
+
+    test.interpolate_attrs = true;
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+
+    dev.attrs = vec![
+        pair("token", "abc"),
+        pair("shared", "${token}-1"),
+        pair("literal", "$${token} costs $5"),
+        pair("token", "def"),
+        pair("latest", "${token}/${shared}"),
+    ];
+    assert_eq!(
+        vec![
+            pair("token", "abc"),
+            pair("shared", "abc-1"),
+            pair("literal", "${token} costs $5"),
+            pair("token", "def"),
+            pair("latest", "def/abc-1"),
+        ],
+        dev.resolved_attrs().unwrap()
+    );
+
+    // references must be to attributes set earlier in the sequence
+    dev.attrs = vec![pair("shared", "${token}"), pair("token", "abc")];
+    let e = dev
+        .resolved_attrs()
+        .expect_err("a forward reference should fail");
+    assert!(format!("{:#}", e).contains("'token'"));
+
+    dev.attrs = vec![pair("token", "abc"), pair("shared", "${token")];
+    dev.resolved_attrs()
+        .expect_err("an unterminated reference should fail");
+
+    // the uuid of the device can be referred to, unless an attribute of that name was set
+    dev.attrs = vec![pair("slot", "2"), pair("name", "gpu-${uuid}-slot-${slot}")];
+    assert_eq!(
+        format!("gpu-{}-slot-2", UUID),
+        dev.resolved_attrs().unwrap()[1].1
+    );
+    dev.attrs = vec![pair("uuid", "x"), pair("name", "${uuid}")];
+    assert_eq!("x", dev.resolved_attrs().unwrap()[1].1);
+
+    // attributes referring to each other are reported as a possible cycle
+    dev.attrs = vec![pair("a", "${b}"), pair("b", "${a}")];
+    let e = dev.resolved_attrs().expect_err("a cycle should fail");
+    assert!(format!("{:#}", e).contains("cycle"), "{:#}", e);
+    dev.attrs = vec![pair("a", "${a}")];
+    let e = dev
+        .resolved_attrs()
+        .expect_err("a self reference should fail");
+    assert!(format!("{:#}", e).contains("cycle"), "{:#}", e);
+}
+
+#[test]
+fn test_device_api() {
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("device-api", "default").with_parent(PARENT, MDEV_TYPE);
+    let mut dev = MDev::new(&test, Uuid::parse_str(UUID).unwrap());
+    dev.device_api()
+        .expect_err("device api of a device without parent should fail");
+
+    dev.parent = Some(PARENT.to_string());
+    dev.device_api()
+        .expect_err("device api of a device without type should fail");
+
+    dev.mdev_type = Some("unknown-type".to_string());
+    dev.device_api()
+        .expect_err("device api of an unsupported type should fail");
+
+    dev.mdev_type = Some(MDEV_TYPE.to_string());
+    assert_eq!("vfio-pci", dev.device_api().unwrap());
+}
+
+#[test]
+fn test_wait() {
+    use std::time::{Duration, Instant};
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const PARENT: &str = "0000:00:03.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let uuid = Uuid::parse_str(UUID).unwrap();
+
+    // the device appears after a delay
+    let test = TestEnvironment::new("wait", "appears");
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            std::thread::sleep(Duration::from_millis(200));
+            test.populate_active_device(UUID, PARENT, MDEV_TYPE);
+        });
+        crate::wait_command(&test, uuid, Duration::from_secs(10), None)
+            .expect("wait command failed unexpectedly");
+    });
+    assert!(test.mdev_base().join(UUID).exists());
+
+    // the device never provides the attribute
+    let start = Instant::now();
+    crate::wait_command(
+        &test,
+        uuid,
+        Duration::from_millis(300),
+        Some("ready".to_string()),
+    )
+    .expect_err("wait command should have timed out");
+    assert!(start.elapsed() >= Duration::from_millis(300));
+
+    fs::write(test.mdev_base().join(UUID).join("ready"), "1").unwrap();
+    crate::wait_command(
+        &test,
+        uuid,
+        Duration::from_millis(300),
+        Some("ready".to_string()),
+    )
+    .expect("wait command failed unexpectedly");
+}
+
+#[test]
+fn test_prune() {
+    use std::io::Cursor;
+
+    init();
+
+    const UUID: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const PARENT: &str = "0000:00:03.0";
+    const GONE: &str = "0000:00:04.0";
+    const MDEV_TYPE: &str = "i915-GVTg_V5_4";
+
+    let test = TestEnvironment::new("prune", "default").with_parent(PARENT, MDEV_TYPE);
+    let def = serde_json::json!({"mdev_type": MDEV_TYPE, "start": "manual", "attrs": []});
+    let test = test
+        .with_definition(UUID, PARENT, &def)
+        .with_definition(UUID2, GONE, &def);
+    let prune = |yes: bool| {
+        crate::prune(&test, yes, false, &mut Cursor::new(""), &mut Vec::new())
+            .map(|devs| devs.iter().map(|d| d.uuid.to_string()).collect::<Vec<_>>())
+    };
+
+    // nothing is removed without confirmation
+    prune(false).expect_err("pruning without confirmation should fail");
+    assert!(test.persist_base().join(GONE).join(UUID2).exists());
+
+    assert_eq!(vec![UUID2], prune(true).unwrap());
+    assert!(!test.persist_base().join(GONE).exists());
+    assert!(test.persist_base().join(PARENT).join(UUID).exists());
+
+    // nothing is left to prune
+    assert!(prune(false).unwrap().is_empty());
+
+    // no parent is considered missing if parent devices can't be looked up at all
+    let test = TestEnvironment::new("prune", "no-parent-class")
+        .with_definition(UUID, PARENT, &def)
+        .with_definition(UUID2, GONE, &def);
+    fs::remove_dir_all(test.parent_base()).unwrap();
+    crate::orphaned_definitions(&test).expect_err("finding orphans should fail");
+    crate::prune(&test, true, false, &mut Cursor::new(""), &mut Vec::new())
+        .expect_err("pruning should fail");
+    assert!(test.persist_base().join(PARENT).join(UUID).exists());
+    assert!(test.persist_base().join(GONE).join(UUID2).exists());
+}
+
+#[test]
+fn test_confirm_bulk() {
+    use std::io::Cursor;
+
+    init();
+
+    let uuids: Vec<Uuid> = (0..7).map(|_| Uuid::new_v4()).collect();
+    let confirm = |yes: bool, interactive: bool, answer: &str| {
+        let mut output = Vec::new();
+        let res = crate::confirm_bulk(
+            "undefine",
+            &uuids,
+            yes,
+            interactive,
+            &mut Cursor::new(answer.to_string()),
+            &mut output,
+        );
+        (res, String::from_utf8(output).unwrap())
+    };
+
+    // without a terminal to ask on, the operation is refused unless confirmed up front
+    let (res, output) = confirm(false, false, "y\n");
+    let e = res.expect_err("a non-interactive bulk operation should be refused");
+    assert!(e.to_string().contains("--yes"));
+    assert!(e.to_string().contains("7 device(s)"));
+    assert!(output.is_empty());
+    let (res, output) = confirm(true, false, "");
+    res.expect("--yes should skip the confirmation");
+    assert!(output.is_empty());
+
+    // the question shows the count and a sample of the devices
+    let (res, output) = confirm(false, true, "y\n");
+    res.expect("answering yes should confirm");
+    assert!(output.starts_with("About to undefine 7 device(s): "));
+    assert!(output.contains(&uuids[4].to_hyphenated().to_string()));
+    assert!(!output.contains(&uuids[5].to_hyphenated().to_string()));
+    assert!(output.contains("and 2 more"));
+
+    for answer in ["n\n", "\n", ""] {
+        let (res, _) = confirm(false, true, answer);
+        res.expect_err("anything but yes should abort");
+    }
+}
+
+#[test]
+fn test_undefine_matching() {
+    init();
+
+    const UUID1: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const UUID3: &str = "4a0a190f-dcf3-4def-9342-c48768f0c940";
+    const PARENT1: &str = "0000:00:02.0";
+    const PARENT2: &str = "0000:00:03.0";
+
+    let def = |t: &str| serde_json::json!({"mdev_type": t, "start": "manual", "attrs": []});
+    let test = TestEnvironment::new("undefine", "matching")
+        .with_definition(UUID1, PARENT1, &def("type-a"))
+        .with_definition(UUID2, PARENT1, &def("type-b"))
+        .with_definition(UUID3, PARENT2, &def("type-a"));
+    let defined = |test: &TestEnvironment| -> Vec<String> {
+        crate::defined_devices(test, None, None)
+            .unwrap()
+            .values()
+            .flatten()
+            .map(|d| d.uuid.to_hyphenated().to_string())
+            .collect()
+    };
+
+    // the dry run only lists the devices
+    let devs = crate::matching_definitions(&test, None, Some("type-a"), None).unwrap();
+    assert_eq!(2, devs.len());
+    assert_eq!(3, defined(&test).len());
+
+    let undefine_matching = |parent, mdev_type, uuid_prefix| {
+        let devs = crate::matching_definitions(&test, parent, mdev_type, uuid_prefix).unwrap();
+        crate::undefine_devices(&test, devs)
+    };
+    let results = undefine_matching(None, Some("type-a"), None);
+    assert_eq!(2, results.len());
+    assert!(results.iter().all(|(_, r)| r.is_ok()));
+    assert_eq!(vec![UUID2], defined(&test));
+    // the parent directory that is now empty is removed, the other one is kept
+    assert!(!test.persist_base().join(PARENT2).exists());
+    assert!(test.persist_base().join(PARENT1).exists());
+
+    // filters are combined
+    let results = undefine_matching(Some(PARENT2), Some("type-b"), None);
+    assert!(results.is_empty());
+    let results = undefine_matching(None, None, Some("59E8"));
+    assert_eq!(1, results.len());
+    assert!(defined(&test).is_empty());
+
+    // only the devices found by the search are undefined, not one defined after it
+    let define = |uuid: &str, parent: &str| {
+        let mut dev = MDev::new(&test, Uuid::parse_str(uuid).unwrap());
+        dev.parent = Some(parent.to_string());
+        dev.mdev_type = Some("type-a".to_string());
+        dev.define().unwrap();
+    };
+    define(UUID1, PARENT1);
+    let devs = crate::matching_definitions(&test, None, Some("type-a"), None).unwrap();
+    assert_eq!(1, devs.len());
+    define(UUID3, PARENT2);
+    let results = crate::undefine_devices(&test, devs);
+    assert_eq!(1, results.len());
+    assert!(results[0].1.is_ok());
+    assert_eq!(vec![UUID3], defined(&test));
+}
+
+#[test]
+fn test_persist_per_parent() {
+    init();
+
+    const UUID1: &str = "976d8cc2-4bfc-43b9-b9f9-f4af2de91ab9";
+    const UUID2: &str = "59e8b599-afdd-4766-a59e-415ef4f5e492";
+    const UUID3: &str = "4a0a190f-dcf3-4def-9342-c48768f0c940";
+    const PARENT1: &str = "0000:00:02.0";
+    const PARENT2: &str = "0000:00:03.0";
+
+    let mut test = TestEnvironment::new("persist-per-parent", "default");
+    test.persist_layout = PersistLayout::PerParent;
+
+    let new_dev = |uuid: &str, parent: &str, mdev_type: &str| {
+        let mut dev = MDev::new(&test, Uuid::parse_str(uuid).unwrap());
+        dev.parent = Some(parent.to_string());
+        dev.mdev_type = Some(mdev_type.to_string());
+        dev.attrs = vec![("foo".to_string(), mdev_type.to_string())];
+        dev
+    };
+    let mut dev1 = new_dev(UUID1, PARENT1, "type-a");
+    let dev2 = new_dev(UUID2, PARENT1, "type-b");
+    let dev3 = new_dev(UUID3, PARENT2, "type-a");
+    dev1.define().unwrap();
+    dev2.define().unwrap();
+    dev3.define().unwrap();
+
+    // definitions of a parent share a single file
+    let path = test.persist_base().join(format!("{}.json", PARENT1));
+    assert_eq!(Some(path.clone()), dev1.persist_path());
+    let defs = crate::mdev::read_aggregate(&path).unwrap();
+    assert_eq!(
+        vec![UUID2, UUID1],
+        defs.keys().map(|k| k.as_str()).collect::<Vec<&str>>()
+    );
+    assert_eq!(dev2.to_json(false, false, false).unwrap(), defs[UUID2]);
+    assert!(dev1.is_defined() && dev2.is_defined() && dev3.is_defined());
+    assert!(!test.persist_base().join(PARENT1).exists());
+
+    let defined = crate::defined_devices(&test, None, None).unwrap();
+    assert_eq!(2, defined[PARENT1].len());
+    assert_eq!(1, defined[PARENT2].len());
+    let mut loaded = MDev::new(&test, Uuid::parse_str(UUID2).unwrap());
+    loaded.parent = Some(PARENT1.to_string());
+    loaded.load_definition().unwrap();
+    assert_eq!(
+        dev2.to_json(false, false, false).unwrap(),
+        loaded.to_json(false, false, false).unwrap()
+    );
+
+    // updating an entry keeps the others
+    dev1.autostart = true;
+    dev1.define().unwrap();
+    let defs = crate::mdev::read_aggregate(&path).unwrap();
+    assert_eq!(dev1.to_json(false, false, false).unwrap(), defs[UUID1]);
+    assert_eq!(dev2.to_json(false, false, false).unwrap(), defs[UUID2]);
+
+    // removing an entry keeps the others, removing the last one removes the file
+    dev1.undefine().unwrap();
+    assert!(!dev1.is_defined());
+    let defs = crate::mdev::read_aggregate(&path).unwrap();
+    assert_eq!(1, defs.len());
+    assert_eq!(dev2.to_json(false, false, false).unwrap(), defs[UUID2]);
+    dev1.undefine()
+        .expect_err("undefining a device twice should fail");
+
+    let devs = crate::matching_definitions(&test, Some(PARENT1), None, None).unwrap();
+    let results = crate::undefine_devices(&test, devs);
+    assert_eq!(1, results.len());
+    assert!(!path.exists());
+    assert!(test
+        .persist_base()
+        .join(format!("{}.json", PARENT2))
+        .exists());
+}